@@ -1,31 +1,87 @@
 struct VertexInput {
    @location(0) position: vec2<f32>,
+   @location(1) style: u32,
+   @location(2) position_low: vec2<f32>,
 }
 
 struct Uniforms {
-  transform: mat4x4<f32>,
-  aspect: f32,
+  // Camera center as a high and low f32, subtracted from the vertex halves separately
+  // so positions keep their precision far from the origin
+  center_high: vec2<f32>,
+  center_low: vec2<f32>,
+  // World units to clip space
+  scale: vec2<f32>,
+  screen_size: vec2<f32>,
+  zoom_level: f32,
+  pixels_per_meter: f32,
 }
 @group(0) @binding(0) 
 var<uniform> uniforms: Uniforms;
 
+// Keep in sync with `GpuStyle` in style.rs
+struct Style {
+  color: vec4<f32>,
+  fill: vec4<f32>,
+  width: f32,
+  min_zoom: f32,
+  max_zoom: f32,
+  z_order: f32,
+  width_unit: u32,
+  join: u32,
+  cap: u32,
+}
+@group(0) @binding(1)
+var<storage, read> styles: array<Style>;
+
+// The vertex and index buffers, read directly by the line shader.
+// Vertices are five words each: x, y, low x, low y and the style index.
+@group(0) @binding(2)
+var<storage, read> vertex_words: array<u32>;
+@group(0) @binding(3)
+var<storage, read> line_indices: array<u32>;
+
+// Large maps are drawn in chunks that each bind a window of the vertex and index
+// buffers, see `Chunk` in graphics.rs. Vertex indices still count from the start of
+// the whole buffer, so the window's start is subtracted from them.
+struct Chunk {
+  first_vertex_word: u32,
+}
+@group(0) @binding(4)
+var<uniform> chunk: Chunk;
+
+const RESTART: u32 = 0xffffffffu;
+const UNIT_METERS: u32 = 1u;
+const JOIN_MITER: u32 = 1u;
+// How a segment ends, either continuing into the next one with a miter or with a cap
+const END_MITER: u32 = 0u;
+const END_BUTT: u32 = 1u;
+const END_SQUARE: u32 = 2u;
+const END_ROUND: u32 = 3u;
+// Below this cosine between the miter and the segment normal the join is drawn round
+const MITER_LIMIT: f32 = 0.25;
+
+fn world_to_clip(high: vec2<f32>, low: vec2<f32>) -> vec2<f32> {
+    let relative = (high - uniforms.center_high) + (low - uniforms.center_low);
+    return relative * uniforms.scale;
+}
+
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(1) color: vec4<f32>,
 };
 
+// Areas, colored with the style's fill
 @vertex
-fn vs_main(
-    @builtin(vertex_index) in_vertex_index: u32,
-    in: VertexInput
-) -> VertexOutput {
+fn vs_fill(in: VertexInput) -> VertexOutput {
     var out: VertexOutput;
-    var pos = uniforms.transform * vec4<f32>(in.position.x, in.position.y * uniforms.aspect, 0.0, 1.0);
+    let style = styles[in.style];
+    var pos = vec4<f32>(world_to_clip(in.position, in.position_low), 0.0, 1.0);
+    // Push areas outside of their zoom range behind the far plane so they get clipped
+    if uniforms.zoom_level < style.min_zoom || uniforms.zoom_level > style.max_zoom {
+        pos.z = 2.0;
+    }
     out.clip_position = pos;
-    // Color based on distance from the center
-    var d = length(pos);
-    out.color = vec4<f32>(d, 1.0 - d, 0.0, 1.0);
-
+    out.color = style.fill;
     return out;
 }
 
@@ -36,5 +92,166 @@ fn fs_main(
 ) -> @location(0) vec4<f32> {
     return in.color;
 }
- 
 
+struct LineOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+    // Pixels along the segment from its start and across from its center line
+    @location(1) local: vec2<f32>,
+    // Segment length and half width in pixels
+    @location(2) @interpolate(flat) shape: vec2<f32>,
+    @location(3) @interpolate(flat) ends: vec2<u32>,
+};
+
+const VERTEX_WORDS: u32 = 5u;
+
+// Where a vertex starts in the chunk's window of `vertex_words`
+fn vertex_word(index: u32) -> u32 {
+    return index * VERTEX_WORDS - chunk.first_vertex_word;
+}
+
+// Position of a vertex on screen, in pixels from the center
+fn to_screen(index: u32) -> vec2<f32> {
+    let base = vertex_word(index);
+    let high = vec2<f32>(bitcast<f32>(vertex_words[base]), bitcast<f32>(vertex_words[base + 1u]));
+    let low = vec2<f32>(bitcast<f32>(vertex_words[base + 2u]), bitcast<f32>(vertex_words[base + 3u]));
+    return world_to_clip(high, low) * 0.5 * uniforms.screen_size;
+}
+
+fn perpendicular(v: vec2<f32>) -> vec2<f32> {
+    return vec2<f32>(-v.y, v.x);
+}
+
+fn safe_normalize(v: vec2<f32>) -> vec2<f32> {
+    let len = length(v);
+    if len < 1e-4 {
+        return vec2<f32>(0.0);
+    }
+    return v / len;
+}
+
+// Picks the end type and returns the miter direction scaled so it reaches the edge of the line
+fn line_end(has_neighbour: bool, neighbour_dir: vec2<f32>, normal: vec2<f32>, style: Style, miter: ptr<function, vec2<f32>>) -> u32 {
+    if !has_neighbour {
+        return style.cap;
+    }
+    if style.join != JOIN_MITER || all(neighbour_dir == vec2<f32>(0.0)) {
+        return END_ROUND;
+    }
+    let m = safe_normalize(perpendicular(neighbour_dir) + normal);
+    let cos_angle = dot(m, normal);
+    if cos_angle < MITER_LIMIT {
+        return END_ROUND;
+    }
+    *miter = m / cos_angle;
+    return END_MITER;
+}
+
+// Draws the segment from `line_indices[segment]` to the next index as a quad of 4 corners
+@vertex
+fn vs_line(
+    @builtin(vertex_index) corner: u32,
+    @builtin(instance_index) segment: u32,
+) -> LineOutput {
+    var out: LineOutput;
+    out.clip_position = vec4<f32>(0.0, 0.0, 2.0, 1.0);
+    let a_index = line_indices[segment];
+    let b_index = line_indices[segment + 1u];
+    if a_index == RESTART || b_index == RESTART {
+        return out;
+    }
+    let style = styles[vertex_words[vertex_word(a_index) + 4u]];
+    if uniforms.zoom_level < style.min_zoom || uniforms.zoom_level > style.max_zoom {
+        return out;
+    }
+
+    let a = to_screen(a_index);
+    let b = to_screen(b_index);
+    var dir = safe_normalize(b - a);
+    if all(dir == vec2<f32>(0.0)) {
+        dir = vec2<f32>(1.0, 0.0);
+    }
+    let normal = perpendicular(dir);
+
+    var width = style.width;
+    if style.width_unit == UNIT_METERS {
+        width *= uniforms.pixels_per_meter;
+    }
+    let half_width = max(width, 1.0) * 0.5;
+    // One extra pixel around the line for the anti-aliased edge
+    let extent = half_width + 1.0;
+
+    let has_prev = segment > 0u && line_indices[segment - 1u] != RESTART;
+    let has_next = segment + 2u < arrayLength(&line_indices) && line_indices[segment + 2u] != RESTART;
+    var prev_dir = vec2<f32>(0.0);
+    if has_prev {
+        prev_dir = safe_normalize(a - to_screen(line_indices[segment - 1u]));
+    }
+    var next_dir = vec2<f32>(0.0);
+    if has_next {
+        next_dir = safe_normalize(to_screen(line_indices[segment + 2u]) - b);
+    }
+    var start_miter = vec2<f32>(0.0);
+    var end_miter = vec2<f32>(0.0);
+    let start = line_end(has_prev, prev_dir, normal, style, &start_miter);
+    let end = line_end(has_next, next_dir, normal, style, &end_miter);
+
+    let at_end = corner / 2u == 1u;
+    let side = select(-1.0, 1.0, corner % 2u == 1u);
+    let point = select(a, b, at_end);
+    let kind = select(start, end, at_end);
+    let outward = select(-dir, dir, at_end);
+    var offset: vec2<f32>;
+    if kind == END_MITER {
+        offset = select(start_miter, end_miter, at_end) * side * extent;
+    } else if kind == END_BUTT {
+        offset = normal * side * extent + outward;
+    } else {
+        offset = normal * side * extent + outward * extent;
+    }
+
+    let corner_pos = point + offset;
+    out.clip_position = vec4<f32>(corner_pos / (0.5 * uniforms.screen_size), 0.0, 1.0);
+    out.color = style.color;
+    out.local = vec2<f32>(dot(corner_pos - a, dir), dot(corner_pos - a, normal));
+    out.shape = vec2<f32>(length(b - a), half_width);
+    out.ends = vec2<u32>(start, end);
+    return out;
+}
+
+// Distance from the center line for a pixel `beyond` pixels past the end of the segment
+fn end_distance(kind: u32, beyond: f32, across: f32, half_width: f32) -> f32 {
+    switch kind {
+        case END_BUTT: {
+            return max(across, beyond + half_width);
+        }
+        case END_SQUARE: {
+            return max(across, beyond);
+        }
+        case END_ROUND: {
+            return length(vec2<f32>(beyond, across));
+        }
+        default: {
+            return across;
+        }
+    }
+}
+
+@fragment
+fn fs_line(in: LineOutput) -> @location(0) vec4<f32> {
+    let along = in.local.x;
+    let across = abs(in.local.y);
+    let len = in.shape.x;
+    let half_width = in.shape.y;
+    var distance = across;
+    if along < 0.0 {
+        distance = end_distance(in.ends.x, -along, across, half_width);
+    } else if along > len {
+        distance = end_distance(in.ends.y, along - len, across, half_width);
+    }
+    let coverage = clamp(half_width + 0.5 - distance, 0.0, 1.0);
+    if coverage <= 0.0 {
+        discard;
+    }
+    return vec4<f32>(in.color.rgb, in.color.a * coverage);
+}
@@ -0,0 +1,631 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Cursor, Read, Write},
+    marker::PhantomData,
+    ops::{Deref, Range},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::JoinHandle,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{bail, Context, Result};
+use bytemuck::Pod;
+use glam::DVec2;
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clip::Clip, hierarchy::ContractionHierarchy, lod::Lod, osm::OSM, profile::Profile,
+    projection::Projection, style::GpuStyle, vertex::Vertex, RawRenderData,
+};
+
+const MAGIC: [u8; 8] = *b"OSMVIEW\0";
+const DATASET_MAGIC: [u8; 8] = *b"OSMDATA\0";
+const HIERARCHY_MAGIC: [u8; 8] = *b"OSMCH\0\0\0";
+/// Bump whenever `RawRenderData`, `OSM` or anything inside of them changes shape
+const FORMAT_VERSION: u32 = 9;
+/// Arrays start on this boundary so they can be cast in place, wider than any of
+/// their alignments
+const ARRAY_ALIGN: usize = 64;
+// Written in pieces so progress can be reported on big extracts
+const WRITE_CHUNK: usize = 64 << 20;
+
+/// Everything besides the source files that changes what `RawRenderData::from_osm` produces
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    pub projection: Projection,
+    /// Source of the style sheet, styles are baked into the vertices
+    pub style: String,
+    pub clip: Option<Clip>,
+    /// Source of the tag filter
+    pub filter: Option<String>,
+    /// Whether the source files are drawn as separate layers instead of merged
+    pub layers: bool,
+}
+
+/// Size and modification time of an input file, which change whenever it's replaced
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileStamp {
+    len: u64,
+    modified: u128,
+}
+
+impl FileStamp {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let metadata =
+            std::fs::metadata(path).with_context(|| format!("reading {}", path.display()))?;
+        Ok(Self {
+            len: metadata.len(),
+            modified: metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos()),
+        })
+    }
+}
+
+/// Identifies the input a cache was built from
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct CacheKey {
+    vertex_size: u32,
+    style_size: u32,
+    /// Arrays are stored in native byte order
+    little_endian: bool,
+    /// Every input file, in the order they were given
+    sources: Vec<FileStamp>,
+    /// OsmChange files applied on top of the source, in order
+    changes: Vec<FileStamp>,
+    settings: Settings,
+}
+
+impl CacheKey {
+    pub fn new(sources: Vec<FileStamp>, changes: Vec<FileStamp>, settings: Settings) -> Self {
+        Self {
+            vertex_size: std::mem::size_of::<Vertex>() as u32,
+            style_size: std::mem::size_of::<GpuStyle>() as u32,
+            little_endian: cfg!(target_endian = "little"),
+            sources,
+            changes,
+            settings,
+        }
+    }
+
+    // Says what changed so it's clear why the cache is rebuilt
+    fn mismatch(&self, cached: &CacheKey) -> Option<&'static str> {
+        if let Some(reason) = self.drawn_differently(cached) {
+            Some(reason)
+        } else if self.sources != cached.sources {
+            Some("the source files changed")
+        } else if self.changes != cached.changes {
+            Some("the change files changed")
+        } else if self.settings.clip != cached.settings.clip {
+            Some("the clip area changed")
+        } else if self.settings.filter != cached.settings.filter {
+            Some("the filter changed")
+        } else {
+            None
+        }
+    }
+
+    // Whether the same ways would come out differently, in which case none of the
+    // cached tiles can be reused
+    fn drawn_differently(&self, cached: &CacheKey) -> Option<&'static str> {
+        if self.vertex_size != cached.vertex_size
+            || self.style_size != cached.style_size
+            || self.little_endian != cached.little_endian
+        {
+            Some("the vertex layout changed")
+        } else if self.settings.projection != cached.settings.projection {
+            Some("the projection changed")
+        } else if self.settings.style != cached.settings.style {
+            Some("the style changed")
+        } else if self.settings.layers != cached.settings.layers {
+            Some("the layer mode changed")
+        } else {
+            None
+        }
+    }
+}
+
+/// Identifies the base files a dataset cache was loaded from, the change files
+/// applied to it are stored next to it so more can be applied later. Datasets are
+/// kept unfiltered and unclipped, see `OSM::restrict`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DatasetKey {
+    pub sources: Vec<FileStamp>,
+}
+
+/// Identifies the road network a contraction hierarchy was built for
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct HierarchyKey {
+    pub dataset: DatasetKey,
+    pub changes: Vec<FileStamp>,
+    pub clip: Option<Clip>,
+    /// Source of the tag filter
+    pub filter: Option<String>,
+    pub profile: Profile,
+    /// Nodes of the `OSMGraph`, which the hierarchy refers to by index
+    pub nodes: usize,
+}
+
+/// The parts of `RawRenderData` small enough to deserialize, the big arrays follow
+/// the header as raw bytes
+#[derive(Serialize, Deserialize)]
+struct Metadata<'a> {
+    lods: Cow<'a, [Lod]>,
+    styles: Cow<'a, [GpuStyle]>,
+    projection: Projection,
+    origin: DVec2,
+    tile_hashes: Cow<'a, HashMap<(i32, i32), u64>>,
+    /// Byte ranges of the vertices, indices and fill indices, relative to the first
+    /// aligned offset after the header
+    arrays: [Range<u64>; 3],
+}
+
+/// A slice that's either built in memory or read straight out of a mapped cache file
+pub enum Array<T> {
+    Owned(Vec<T>),
+    Mapped {
+        map: Arc<Mmap>,
+        range: Range<usize>,
+        _marker: PhantomData<T>,
+    },
+}
+
+impl<T: Pod> Array<T> {
+    fn mapped(map: &Arc<Mmap>, range: Range<usize>) -> Result<Self> {
+        let bytes = map.get(range.clone()).context("array is past the end")?;
+        bytemuck::try_cast_slice::<u8, T>(bytes)
+            .map_err(|err| anyhow::anyhow!("array is misaligned: {}", err))?;
+        Ok(Array::Mapped {
+            map: map.clone(),
+            range,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T> From<Vec<T>> for Array<T> {
+    fn from(vec: Vec<T>) -> Self {
+        Array::Owned(vec)
+    }
+}
+
+impl<T: Pod> Deref for Array<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Array::Owned(vec) => vec,
+            // Checked when the array was mapped
+            Array::Mapped { map, range, .. } => bytemuck::cast_slice(&map[range.clone()]),
+        }
+    }
+}
+
+fn align(offset: usize) -> usize {
+    offset.next_multiple_of(ARRAY_ALIGN)
+}
+
+fn read_header(reader: &mut impl Read, magic: [u8; 8]) -> Result<()> {
+    let mut found = [0; 8];
+    reader.read_exact(&mut found).context("file is too short")?;
+    if found != magic {
+        bail!("not a cache file");
+    }
+    let mut word = [0; 4];
+    reader.read_exact(&mut word)?;
+    let version = u32::from_le_bytes(word);
+    if version != FORMAT_VERSION {
+        bail!("format version {} instead of {}", version, FORMAT_VERSION);
+    }
+    Ok(())
+}
+
+/// Maps the cache if it was made from the same input by this version, otherwise the
+/// error says why it can't be used. Only the header is parsed, the vertex and index
+/// arrays stay in the file until they're uploaded.
+pub fn read(path: impl AsRef<Path>, key: &CacheKey) -> Result<RawRenderData> {
+    read_checked(path.as_ref(), |cached| key.mismatch(cached))
+}
+
+/// Maps a cache made from other input, but drawn the same way, so its unchanged tiles
+/// can be reused by `RawRenderData::updated`
+pub fn read_for_update(path: impl AsRef<Path>, key: &CacheKey) -> Result<RawRenderData> {
+    read_checked(path.as_ref(), |cached| key.drawn_differently(cached))
+}
+
+fn read_checked(
+    path: &Path,
+    mismatch: impl FnOnce(&CacheKey) -> Option<&'static str>,
+) -> Result<RawRenderData> {
+    let file = File::open(path)?;
+    // Safety: caches are only ever replaced by renaming a new file over them, so
+    // the mapped file doesn't change underneath us. On Unix the mapping keeps the
+    // old file alive, on Windows the rename fails while it's mapped and the cache is
+    // just not updated, which is why callers drop the mapping before rewriting it.
+    let map = Arc::new(unsafe { Mmap::map(&file)? });
+    let mut reader = Cursor::new(&map[..]);
+
+    read_header(&mut reader, MAGIC)?;
+    let cached: CacheKey = bincode::deserialize_from(&mut reader)?;
+    if let Some(reason) = mismatch(&cached) {
+        bail!("{}", reason);
+    }
+    let metadata: Metadata = bincode::deserialize_from(&mut reader)?;
+
+    let start = align(reader.position() as usize);
+    let [vertices, indices, fill_indices] = metadata
+        .arrays
+        .map(|range| start + range.start as usize..start + range.end as usize);
+    Ok(RawRenderData {
+        vertices: Array::mapped(&map, vertices)?,
+        indices: Array::mapped(&map, indices)?,
+        fill_indices: Array::mapped(&map, fill_indices)?,
+        lods: metadata.lods.into_owned(),
+        styles: metadata.styles.into_owned(),
+        projection: metadata.projection,
+        origin: metadata.origin,
+        tile_hashes: metadata.tile_hashes.into_owned(),
+    })
+}
+
+/// Writes the render data atomically, see `write_atomically`
+pub fn write(path: impl AsRef<Path>, key: &CacheKey, data: &RawRenderData) -> Result<()> {
+    let path = path.as_ref();
+    let arrays: [&[u8]; 3] = [
+        bytemuck::cast_slice(&data.vertices),
+        bytemuck::cast_slice(&data.indices),
+        bytemuck::cast_slice(&data.fill_indices),
+    ];
+    let mut offset = 0;
+    let ranges = arrays.map(|bytes| {
+        let start = align(offset);
+        offset = start + bytes.len();
+        start as u64..offset as u64
+    });
+
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut header, key)?;
+    bincode::serialize_into(
+        &mut header,
+        &Metadata {
+            lods: Cow::Borrowed(&data.lods),
+            styles: Cow::Borrowed(&data.styles),
+            projection: data.projection,
+            origin: data.origin,
+            tile_hashes: Cow::Borrowed(&data.tile_hashes),
+            arrays: ranges.clone(),
+        },
+    )?;
+    let header_len = align(header.len());
+    header.resize(header_len, 0);
+    let total = header_len + offset;
+
+    write_atomically(path, |writer| {
+        writer.write_all(&header)?;
+        let mut written = 0;
+        for (bytes, range) in arrays.iter().zip(&ranges) {
+            // Padding up to the array's aligned start
+            writer.write_all(&vec![0; range.start as usize - written])?;
+            written = range.start as usize;
+            for chunk in bytes.chunks(WRITE_CHUNK) {
+                writer.write_all(chunk)?;
+                written += chunk.len();
+                eprintln!("Writing cache: {}%", (header_len + written) * 100 / total);
+            }
+        }
+        Ok(())
+    })?;
+    eprintln!("Wrote cache to {} ({} bytes)", path.display(), total);
+    Ok(())
+}
+
+/// Writes to a temporary file next to `path` and renames it into place, so a reader
+/// never sees a half written file even if the process dies part way through
+fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<()>,
+) -> Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(".{}.tmp", std::process::id()));
+    let result = File::create(&temp_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+            writer.into_inner()?.sync_all()?;
+            Ok(())
+        })
+        .and_then(|()| Ok(std::fs::rename(&temp_path, path)?));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result.with_context(|| format!("writing {}", path.display()))
+}
+
+/// Loads a dataset saved by `write_dataset` along with the change files that were
+/// applied to it
+pub fn read_dataset(path: impl AsRef<Path>, key: &DatasetKey) -> Result<(OSM, Vec<FileStamp>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    read_header(&mut reader, DATASET_MAGIC)?;
+    let cached: DatasetKey = bincode::deserialize_from(&mut reader)?;
+    if cached.sources != key.sources {
+        bail!("the source files changed");
+    }
+    Ok(bincode::deserialize_from(&mut reader)?)
+}
+
+pub fn write_dataset(
+    path: impl AsRef<Path>,
+    key: &DatasetKey,
+    osm: &OSM,
+    applied: &[FileStamp],
+) -> Result<()> {
+    let path = path.as_ref();
+    write_atomically(path, |writer| {
+        writer.write_all(&DATASET_MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut *writer, key)?;
+        bincode::serialize_into(&mut *writer, &(osm, applied))?;
+        Ok(())
+    })?;
+    eprintln!("Wrote dataset to {}", path.display());
+    Ok(())
+}
+
+pub fn read_hierarchy(path: impl AsRef<Path>, key: &HierarchyKey) -> Result<ContractionHierarchy> {
+    let mut reader = BufReader::new(File::open(path)?);
+    read_header(&mut reader, HIERARCHY_MAGIC)?;
+    let cached: HierarchyKey = bincode::deserialize_from(&mut reader)?;
+    if cached.dataset != key.dataset {
+        bail!("the source files changed");
+    } else if cached.changes != key.changes {
+        bail!("the change files changed");
+    } else if cached.clip != key.clip {
+        bail!("the clip area changed");
+    } else if cached.filter != key.filter {
+        bail!("the filter changed");
+    } else if cached.profile != key.profile {
+        bail!("the profile changed");
+    } else if cached.nodes != key.nodes {
+        bail!("the road network changed");
+    }
+    Ok(bincode::deserialize_from(&mut reader)?)
+}
+
+pub fn write_hierarchy(
+    path: impl AsRef<Path>,
+    key: &HierarchyKey,
+    hierarchy: &ContractionHierarchy,
+) -> Result<()> {
+    let path = path.as_ref();
+    write_atomically(path, |writer| {
+        writer.write_all(&HIERARCHY_MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut *writer, key)?;
+        bincode::serialize_into(&mut *writer, hierarchy)?;
+        Ok(())
+    })?;
+    eprintln!("Wrote contraction hierarchy to {}", path.display());
+    Ok(())
+}
+
+/// Writes the cache on its own thread so the viewer can start right away
+pub fn write_in_background(
+    path: PathBuf,
+    key: CacheKey,
+    data: Arc<RawRenderData>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        if let Err(err) = write(&path, &key, &data) {
+            eprintln!("Failed to write cache: {:#}", err);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{osm::LoadOptions, style::StyleSheet};
+
+    // Removed again when dropped, whether the test wrote to it or not
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new() -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            Self(std::env::temp_dir().join(format!(
+                "osm-view-cache-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            )))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn key() -> CacheKey {
+        let stamp = FileStamp {
+            len: 1_000,
+            modified: 1,
+        };
+        CacheKey::new(
+            vec![stamp],
+            Vec::new(),
+            Settings {
+                projection: Projection::default(),
+                style: String::new(),
+                clip: None,
+                filter: None,
+                layers: false,
+            },
+        )
+    }
+
+    // A road and a lake, so every array has something in it
+    fn render_data() -> RawRenderData {
+        let osm = OSM::from_xml(
+            r#"<osm version="0.6">
+                <node id="1" lat="0" lon="0"/>
+                <node id="2" lat="0.01" lon="0.01"/>
+                <node id="3" lat="0" lon="0.02"/>
+                <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/>
+                    <tag k="highway" v="primary"/></way>
+                <way id="11"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="1"/>
+                    <tag k="natural" v="water"/></way>
+            </osm>"#,
+            &LoadOptions::default(),
+        )
+        .unwrap();
+        RawRenderData::from_osm(&osm, &StyleSheet::default(), Projection::default())
+    }
+
+    fn written() -> TempPath {
+        let path = TempPath::new();
+        write(&path.0, &key(), &render_data()).unwrap();
+        path
+    }
+
+    fn read_error(path: &Path, key: &CacheKey) -> String {
+        match read(path, key) {
+            Ok(_) => panic!("read a broken cache"),
+            Err(err) => format!("{:#}", err),
+        }
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let data = render_data();
+        assert!(!data.vertices.is_empty() && !data.fill_indices.is_empty());
+        let path = written();
+        let read = read(&path.0, &key()).unwrap();
+
+        assert!(matches!(read.vertices, Array::Mapped { .. }));
+        let bytes = |vertices: &[Vertex]| bytemuck::cast_slice::<_, u8>(vertices).to_vec();
+        assert_eq!(bytes(&read.vertices), bytes(&data.vertices));
+        assert_eq!(*read.indices, *data.indices);
+        assert_eq!(*read.fill_indices, *data.fill_indices);
+        assert_eq!(
+            bytemuck::cast_slice::<_, u8>(&read.styles),
+            bytemuck::cast_slice::<_, u8>(&data.styles)
+        );
+        assert_eq!(read.lods.len(), data.lods.len());
+        for (a, b) in read.lods.iter().zip(&data.lods) {
+            let ranges = |lod: &Lod| {
+                lod.batches
+                    .iter()
+                    .map(|batch| [&batch.vertices, &batch.lines, &batch.fills].map(Clone::clone))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(ranges(a), ranges(b));
+        }
+        assert_eq!(read.projection, data.projection);
+        assert_eq!(read.origin, data.origin);
+        assert_eq!(read.tile_hashes, data.tile_hashes);
+    }
+
+    #[test]
+    fn says_why_the_key_does_not_match() {
+        let reason = |change: fn(&mut CacheKey)| {
+            let mut changed = key();
+            change(&mut changed);
+            changed.mismatch(&key())
+        };
+        let layout = Some("the vertex layout changed");
+        assert_eq!(reason(|key| key.vertex_size += 4), layout);
+        assert_eq!(reason(|key| key.style_size += 4), layout);
+        assert_eq!(reason(|key| key.little_endian = !key.little_endian), layout);
+        assert_eq!(
+            reason(|key| key.settings.projection = Projection::Equirectangular),
+            Some("the projection changed")
+        );
+        assert_eq!(
+            reason(|key| key.settings.style = "way {}".into()),
+            Some("the style changed")
+        );
+        assert_eq!(
+            reason(|key| key.settings.layers = true),
+            Some("the layer mode changed")
+        );
+        assert_eq!(
+            reason(|key| key.sources[0].len += 1),
+            Some("the source files changed")
+        );
+        assert_eq!(
+            reason(|key| key.changes.push(key.sources[0])),
+            Some("the change files changed")
+        );
+        assert_eq!(
+            reason(|key| key.settings.clip = Some("0,0,1,1".parse().unwrap())),
+            Some("the clip area changed")
+        );
+        assert_eq!(
+            reason(|key| key.settings.filter = Some("highway".into())),
+            Some("the filter changed")
+        );
+        assert_eq!(key().mismatch(&key()), None);
+
+        // The tiles of a cache from other input can still be reused
+        let path = written();
+        let mut filtered = key();
+        filtered.settings.filter = Some("highway".into());
+        assert_eq!(read_error(&path.0, &filtered), "the filter changed");
+        assert!(read_for_update(&path.0, &filtered).is_ok());
+        let mut restyled = key();
+        restyled.settings.style = "way {}".into();
+        assert!(read_for_update(&path.0, &restyled).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let path = written();
+        let bytes = std::fs::read(&path.0).unwrap();
+        for len in [0, 4, 10, 40, bytes.len() - 4] {
+            std::fs::write(&path.0, &bytes[..len]).unwrap();
+            assert!(read(&path.0, &key()).is_err(), "read {} bytes", len);
+        }
+        assert_eq!(read_error(&path.0, &key()), "array is past the end");
+    }
+
+    #[test]
+    fn rejects_other_format_versions() {
+        let path = written();
+        let mut bytes = std::fs::read(&path.0).unwrap();
+        bytes[8..12].copy_from_slice(&(FORMAT_VERSION - 1).to_le_bytes());
+        std::fs::write(&path.0, &bytes).unwrap();
+        assert_eq!(
+            read_error(&path.0, &key()),
+            format!(
+                "format version {} instead of {}",
+                FORMAT_VERSION - 1,
+                FORMAT_VERSION
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_misaligned_arrays() {
+        let path = TempPath::new();
+        std::fs::write(&path.0, [0; 64]).unwrap();
+        let file = File::open(&path.0).unwrap();
+        let map = Arc::new(unsafe { Mmap::map(&file).unwrap() });
+        assert!(Array::<u32>::mapped(&map, 4..12).is_ok());
+        let err = Array::<u32>::mapped(&map, 2..10).err().unwrap();
+        assert!(err.to_string().starts_with("array is misaligned"));
+        // Not a whole number of elements either
+        assert!(Array::<u32>::mapped(&map, 4..10).is_err());
+        assert!(Array::<u32>::mapped(&map, 60..68).is_err());
+    }
+}
@@ -1,31 +1,73 @@
-use glam::{Mat4, Vec2, Vec3, Vec3Swizzles};
+use glam::{DVec2, Vec2};
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+use crate::projection::Projection;
+
+/// Splits a position into a coarse f32 and the f32 remainder, the shader subtracts the
+/// camera's halves separately so small differences survive far from the origin
+pub fn split_f64(pos: DVec2) -> (Vec2, Vec2) {
+    let high = pos.as_vec2();
+    let low = (pos - high.as_dvec2()).as_vec2();
+    (high, low)
+}
+
+#[derive(Copy, Clone, Debug)]
 pub struct Camera {
-    pub offset: Vec2,
-    pub zoom: f32,
+    /// World position at the center of the screen, relative to `origin`
+    pub center: DVec2,
+    pub zoom: f64,
+    pub projection: Projection,
+    /// Projected position that the vertices are relative to
+    pub origin: DVec2,
 }
 
 impl Camera {
-    pub fn new() -> Self {
+    pub fn new(projection: Projection, origin: DVec2) -> Self {
         Self {
-            offset: Vec2::new(0.0, 0.0),
+            center: DVec2::ZERO,
             zoom: 1.0,
+            projection,
+            origin,
         }
     }
 
-    // (width, height) -> (1, 1)
-    pub fn screen_to_world(&self, screen_pos: Vec2, screen_size: Vec2) -> Vec2 {
-        let screen_pos = Vec2::new(screen_pos.x as f32, screen_size.y - screen_pos.y as f32); // Flip Y-axis
+    /// World units to normalized device coordinates, y is stretched by the aspect
+    /// ratio so the map isn't squashed
+    pub fn scale(&self, screen_size: Vec2) -> DVec2 {
+        let aspect = (screen_size.x / screen_size.y) as f64;
+        DVec2::new(self.zoom, self.zoom * aspect)
+    }
+
+    /// Corners of the screen in world units relative to `origin`, grown by `margin` pixels
+    pub fn visible_bounds(&self, screen_size: Vec2, margin: f32) -> (DVec2, DVec2) {
+        let half = (Vec2::ONE + 2.0 * margin / screen_size).as_dvec2() / self.scale(screen_size);
+        (self.center - half, self.center + half)
+    }
+
+    // [-1, 1] -> world relative to `origin`
+    pub fn ndc_to_world(&self, ndc: Vec2, screen_size: Vec2) -> DVec2 {
+        self.center + ndc.as_dvec2() / self.scale(screen_size)
+    }
+
+    // (width, height) -> (lon, lat)
+    pub fn screen_to_world(&self, screen_pos: Vec2, screen_size: Vec2) -> DVec2 {
+        let screen_pos = Vec2::new(screen_pos.x, screen_size.y - screen_pos.y); // Flip Y-axis
         let normalized_pos = (screen_pos / screen_size) * 2.0 - Vec2::ONE; // Convert to [-1, 1] range
-        (normalized_pos - self.offset) / self.zoom
+        let world = self.ndc_to_world(normalized_pos, screen_size);
+        self.projection.unproject(world + self.origin)
     }
 
-    pub fn matrix(&self) -> Mat4 {
-        let translation = Mat4::from_translation(Vec3::new(self.offset.x, self.offset.y, 0.0));
-        let scaling = Mat4::from_scale(Vec3::new(self.zoom, self.zoom, 1.0));
-        translation * scaling
+    /// Slippy map zoom level, where the 360 degrees of longitude span 256 * 2^level pixels
+    pub fn zoom_level(&self, screen_width: f32) -> f32 {
+        // One world unit is a degree and the screen is two units wide at zoom 1
+        let pixels_per_degree = self.zoom * screen_width as f64 / 2.0;
+        (pixels_per_degree * 360.0 / 256.0).log2() as f32
+    }
+
+    /// Scale at the center of the screen
+    pub fn pixels_per_meter(&self, screen_size: Vec2) -> f32 {
+        let center = self.projection.unproject(self.center + self.origin);
+        let pixels_per_unit = self.zoom * screen_size.x as f64 / 2.0;
+        (pixels_per_unit / self.projection.meters_per_unit(center.y)) as f32
     }
 }
 
@@ -38,9 +80,9 @@ pub struct CameraController {
 }
 
 impl CameraController {
-    pub fn new(screen_size: Vec2) -> Self {
+    pub fn new(screen_size: Vec2, projection: Projection, origin: DVec2) -> Self {
         Self {
-            camera: Camera::new(),
+            camera: Camera::new(projection, origin),
             screen_size,
             mouse_pos: Vec2::new(0.0, 0.0),
             mouse_down: false,
@@ -55,11 +97,10 @@ impl CameraController {
         );
         if self.mouse_down {
             // Calculate the difference in normalized space
-            let delta = Vec2::new(new_pos.x - self.mouse_pos.x, new_pos.y - self.mouse_pos.y);
+            let delta = new_pos - self.mouse_pos;
 
-            // Adjust the camera offset
-            self.camera.offset.x += delta.x;
-            self.camera.offset.y += delta.y;
+            // Move the camera the opposite way so the map follows the cursor
+            self.camera.center -= delta.as_dvec2() / self.camera.scale(size);
         }
         self.mouse_pos = new_pos;
         // self.camera.zoom += self.scroll_velocity * 0.1;
@@ -68,56 +109,13 @@ impl CameraController {
         self.mouse_down = down;
     }
 
-    // pub fn scroll(&mut self, amount: f32) {
-    //     self.scroll_velocity = amount;
-    //     let mouse_pos = self
-    //         .matrix()
-    //         .inverse()
-    //         .transform_vector3(self.mouse_pos.extend(0.0))
-    //         .xy();
-    //     self.camera.zoom *= 1.0 + amount * 0.1;
-    //     let new_mouse_pos = self
-    //         .matrix()
-    //         // .inverse()
-    //         .transform_point3(Vec3::new(mouse_pos.x, mouse_pos.y, 0.0))
-    //         .xy();
-    //     self.camera.offset += self.mouse_pos - new_mouse_pos;
-    // }
-
     pub fn scroll(&mut self, amount: f32) {
-      // Pre-calculate zoom factor
-      let zoom_factor = 1.0 + amount * 0.1;
-      let new_zoom = self.camera.zoom * zoom_factor;
-  
-      // Calculate the mouse position in world space before zooming
-      let inv_zoom = 1.0 / self.camera.zoom;
-      let mouse_world_x = (self.mouse_pos.x - self.camera.offset.x) * inv_zoom;
-      let mouse_world_y = (self.mouse_pos.y - self.camera.offset.y) * inv_zoom;
-  
-      // Update zoom
-      self.camera.zoom = new_zoom;
-  
-      // Calculate new offset
-      let new_mouse_screen_x = mouse_world_x * new_zoom + self.camera.offset.x;
-      let new_mouse_screen_y = mouse_world_y * new_zoom + self.camera.offset.y;
-  
-      // Update offset
-      self.camera.offset.x += self.mouse_pos.x - new_mouse_screen_x;
-      self.camera.offset.y += self.mouse_pos.y - new_mouse_screen_y;
-  }
-
-  //   pub fn scroll(&mut self, amount: f32) {
-  //     let mat = self.matrix();
-  //     let inv_mat = mat.inverse();
-  //     let mouse_pos =
-  //         inv_mat.transform_point3(Vec3::new(self.mouse_pos.x, self.mouse_pos.y, 0.0));
-  //     let zoom = self.camera.zoom * (1.0 + amount * 0.1);
-  //     self.camera.zoom = zoom;
-  //     let new_mat = self.matrix();
-  //     let new_mouse_pos = new_mat.transform_point3(Vec3::new(mouse_pos.x, mouse_pos.y, 0.0));
-  //     self.camera.offset.x += self.mouse_pos.x - new_mouse_pos.x;
-  //     self.camera.offset.y += self.mouse_pos.y - new_mouse_pos.y;
-  // }
+        // Keep the point under the mouse in place while zooming
+        let before = self.camera.ndc_to_world(self.mouse_pos, self.screen_size);
+        self.camera.zoom *= 1.0 + amount as f64 * 0.1;
+        let after = self.camera.ndc_to_world(self.mouse_pos, self.screen_size);
+        self.camera.center += before - after;
+    }
 
     pub fn apply_velocity(&mut self) {
         if self.scroll_velocity.abs() > 0.01 {
@@ -126,15 +124,15 @@ impl CameraController {
         }
     }
 
-    pub fn screen_to_world(&self, screen_pos: Vec2) -> Vec2 {
+    pub fn reset(&mut self) {
+        self.camera = Camera::new(self.camera.projection, self.camera.origin);
+    }
+
+    pub fn screen_to_world(&self, screen_pos: Vec2) -> DVec2 {
         self.camera.screen_to_world(screen_pos, self.screen_size)
     }
 
     pub fn resize(&mut self, size: Vec2) {
         self.screen_size = size;
     }
-
-    pub fn matrix(&self) -> Mat4 {
-        self.camera.matrix()
-    }
 }
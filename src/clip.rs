@@ -0,0 +1,234 @@
+use std::{path::Path, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use glam::DVec2;
+use serde::{Deserialize, Serialize};
+
+/// Area to keep while loading, in (lon, lat). Rings follow the even-odd rule, so a
+/// ring inside another one cuts a hole into it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Clip {
+    rings: Vec<Vec<DVec2>>,
+    min: DVec2,
+    max: DVec2,
+}
+
+impl Clip {
+    pub fn bbox(min: DVec2, max: DVec2) -> Self {
+        let ring = vec![
+            min,
+            DVec2::new(max.x, min.y),
+            max,
+            DVec2::new(min.x, max.y),
+            min,
+        ];
+        Self {
+            rings: vec![ring],
+            min,
+            max,
+        }
+    }
+
+    /// Reads an Osmosis polygon filter file: a name line, then sections each made of
+    /// a ring name (starting with `!` for holes), coordinate lines and `END`, and a
+    /// final `END`
+    pub fn load_poly(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let source =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::parse_poly(&source).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    pub fn parse_poly(source: &str) -> Result<Self> {
+        let mut lines = source
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty());
+        lines.next().context("missing name")?;
+        let mut rings = Vec::new();
+        loop {
+            match lines.next() {
+                Some("END") => break,
+                Some(_) => {}
+                None => bail!("missing final END"),
+            }
+            let mut ring = Vec::new();
+            loop {
+                let line = lines.next().context("ring is missing END")?;
+                if line == "END" {
+                    break;
+                }
+                let mut coords = line.split_whitespace().map(f64::from_str);
+                match (coords.next(), coords.next()) {
+                    (Some(Ok(lon)), Some(Ok(lat))) => ring.push(DVec2::new(lon, lat)),
+                    _ => bail!("invalid coordinate `{}`", line),
+                }
+            }
+            if ring.len() < 3 {
+                bail!("ring with {} points", ring.len());
+            }
+            if ring.first() != ring.last() {
+                ring.push(ring[0]);
+            }
+            rings.push(ring);
+        }
+        if rings.is_empty() {
+            bail!("no rings");
+        }
+
+        let points = rings.iter().flatten();
+        let min = points.clone().fold(DVec2::MAX, |min, &p| min.min(p));
+        let max = points.fold(DVec2::MIN, |max, &p| max.max(p));
+        Ok(Self { rings, min, max })
+    }
+
+    pub fn bounds(&self) -> (DVec2, DVec2) {
+        (self.min, self.max)
+    }
+
+    pub fn contains(&self, pos: DVec2) -> bool {
+        if pos.cmplt(self.min).any() || pos.cmpgt(self.max).any() {
+            return false;
+        }
+        let mut inside = false;
+        for pair in self.rings.iter().flat_map(|ring| ring.windows(2)) {
+            let (a, b) = (pair[0], pair[1]);
+            if (a.y > pos.y) != (b.y > pos.y) {
+                let x = a.x + (pos.y - a.y) / (b.y - a.y) * (b.x - a.x);
+                if pos.x < x {
+                    inside = !inside;
+                }
+            }
+        }
+        inside
+    }
+
+    /// Where the segment from `a` to `b` crosses the boundary, as sorted fractions of
+    /// the way from `a` to `b`
+    pub fn crossings(&self, a: DVec2, b: DVec2) -> Vec<f64> {
+        if a.max(b).cmplt(self.min).any() || a.min(b).cmpgt(self.max).any() {
+            return Vec::new();
+        }
+        let ab = b - a;
+        let mut crossings = Vec::new();
+        for pair in self.rings.iter().flat_map(|ring| ring.windows(2)) {
+            let (c, d) = (pair[0], pair[1]);
+            let cd = d - c;
+            let denominator = ab.perp_dot(cd);
+            if denominator == 0.0 {
+                continue;
+            }
+            let t = (c - a).perp_dot(cd) / denominator;
+            let u = (c - a).perp_dot(ab) / denominator;
+            if (0.0..1.0).contains(&t) && (0.0..1.0).contains(&u) {
+                crossings.push(t);
+            }
+        }
+        crossings.sort_by(f64::total_cmp);
+        crossings
+    }
+}
+
+impl FromStr for Clip {
+    type Err = anyhow::Error;
+
+    /// `min_lon,min_lat,max_lon,max_lat`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let coords = s
+            .split(',')
+            .map(|coord| coord.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("invalid bbox `{}`", s))?;
+        let [min_lon, min_lat, max_lon, max_lat] = coords[..] else {
+            bail!("bbox `{}` needs four coordinates", s);
+        };
+        if min_lon >= max_lon || min_lat >= max_lat {
+            bail!("bbox `{}` is empty", s);
+        }
+        Ok(Self::bbox(
+            DVec2::new(min_lon, min_lat),
+            DVec2::new(max_lon, max_lat),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 4 by 4 square with a 2 by 2 hole in the middle
+    const SQUARE_WITH_HOLE: &str = "
+        square
+        outer
+            0 0
+            4 0
+            4 4
+            0 4
+        END
+        !hole
+            1 1
+            3 1
+            3 3
+            1 3
+            1 1
+        END
+        END
+    ";
+
+    #[test]
+    fn parses_poly_files() {
+        let clip = Clip::parse_poly(SQUARE_WITH_HOLE).unwrap();
+        assert_eq!(clip.rings.len(), 2);
+        // Rings are closed whether or not the file repeats the first point
+        assert!(clip.rings.iter().all(|ring| ring.len() == 5));
+        assert_eq!(clip.bounds(), (DVec2::ZERO, DVec2::splat(4.0)));
+
+        for (source, error) in [
+            ("", "missing name"),
+            ("name\nring\n0 0\n1 0\n1 1\nEND\n", "missing final END"),
+            ("name\nring\n0 0\n1 0\n1 1\n", "ring is missing END"),
+            (
+                "name\nring\n0 0\n1 x\n1 1\nEND\nEND",
+                "invalid coordinate `1 x`",
+            ),
+            ("name\nring\n0 0\n1 0\nEND\nEND", "ring with 2 points"),
+            ("name\nEND", "no rings"),
+        ] {
+            assert_eq!(Clip::parse_poly(source).unwrap_err().to_string(), error);
+        }
+    }
+
+    #[test]
+    fn holes_follow_the_even_odd_rule() {
+        let clip = Clip::parse_poly(SQUARE_WITH_HOLE).unwrap();
+        assert!(clip.contains(DVec2::new(0.5, 0.5)));
+        assert!(clip.contains(DVec2::new(3.5, 2.0)));
+        assert!(!clip.contains(DVec2::new(2.0, 2.0)));
+        assert!(!clip.contains(DVec2::new(5.0, 2.0)));
+        assert!(!clip.contains(DVec2::new(-1.0, -1.0)));
+    }
+
+    #[test]
+    fn crossings_are_sorted_along_the_segment() {
+        let clip = Clip::parse_poly(SQUARE_WITH_HOLE).unwrap();
+        // Right to left through the outer ring and both sides of the hole
+        let crossings = clip.crossings(DVec2::new(5.0, 2.0), DVec2::new(-5.0, 2.0));
+        assert_eq!(crossings, [0.1, 0.2, 0.4, 0.5]);
+        assert!(clip
+            .crossings(DVec2::new(0.5, 0.5), DVec2::new(3.5, 0.5))
+            .is_empty());
+        assert!(clip
+            .crossings(DVec2::new(5.0, 5.0), DVec2::new(6.0, 6.0))
+            .is_empty());
+    }
+
+    #[test]
+    fn parses_bboxes() {
+        let clip: Clip = "1, 2, 3, 4".parse().unwrap();
+        assert_eq!(clip.bounds(), (DVec2::new(1.0, 2.0), DVec2::new(3.0, 4.0)));
+        assert!(clip.contains(DVec2::new(2.0, 3.0)));
+        assert!("1,2,3".parse::<Clip>().is_err());
+        assert!("3,2,1,4".parse::<Clip>().is_err());
+        assert!("1,2,x,4".parse::<Clip>().is_err());
+    }
+}
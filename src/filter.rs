@@ -0,0 +1,195 @@
+use std::{
+    collections::HashMap,
+    iter::Peekable,
+    str::{FromStr, SplitWhitespace},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::style::Condition;
+
+/// Decides which elements are loaded, e.g. `highway=* and not highway=footway` or
+/// `building or (natural=water and not water=pond)`. Terms are the same `key=value`,
+/// `key=*` and `key` conditions as style selectors, plus `key!=value`. Terms next to
+/// each other without an operator have to match together, like in a selector.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Tag(Condition),
+    Not(Box<Filter>),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+}
+
+impl Filter {
+    pub fn matches(&self, tags: &HashMap<String, String>) -> bool {
+        match self {
+            Filter::Tag(condition) => condition.matches(tags),
+            Filter::Not(filter) => !filter.matches(tags),
+            Filter::And(filters) => filters.iter().all(|filter| filter.matches(tags)),
+            Filter::Or(filters) => filters.iter().any(|filter| filter.matches(tags)),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Parentheses don't need spaces around them
+        let spaced = s.replace('(', " ( ").replace(')', " ) ");
+        let mut tokens = spaced.split_whitespace().peekable();
+        let filter = parse_or(&mut tokens).with_context(|| format!("Invalid filter `{}`", s))?;
+        if let Some(token) = tokens.next() {
+            bail!("Invalid filter `{}`: Unexpected `{}`", s, token);
+        }
+        Ok(filter)
+    }
+}
+
+type Tokens<'a> = Peekable<SplitWhitespace<'a>>;
+
+fn parse_or(tokens: &mut Tokens) -> Result<Filter> {
+    let mut filters = vec![parse_and(tokens)?];
+    while tokens.next_if_eq(&"or").is_some() {
+        filters.push(parse_and(tokens)?);
+    }
+    Ok(if filters.len() == 1 {
+        filters.pop().unwrap()
+    } else {
+        Filter::Or(filters)
+    })
+}
+
+fn parse_and(tokens: &mut Tokens) -> Result<Filter> {
+    let mut filters = vec![parse_not(tokens)?];
+    loop {
+        if tokens.next_if_eq(&"and").is_none()
+            && matches!(tokens.peek(), None | Some(&")") | Some(&"or"))
+        {
+            break;
+        }
+        filters.push(parse_not(tokens)?);
+    }
+    Ok(if filters.len() == 1 {
+        filters.pop().unwrap()
+    } else {
+        Filter::And(filters)
+    })
+}
+
+fn parse_not(tokens: &mut Tokens) -> Result<Filter> {
+    match tokens.next() {
+        Some("not") => Ok(Filter::Not(Box::new(parse_not(tokens)?))),
+        Some("(") => {
+            let filter = parse_or(tokens)?;
+            if tokens.next() != Some(")") {
+                bail!("Missing `)`");
+            }
+            Ok(filter)
+        }
+        Some(token @ (")" | "and" | "or")) => bail!("Unexpected `{}`", token),
+        Some(term) => match term.split_once("!=") {
+            Some((key, value)) => Ok(Filter::Not(Box::new(Filter::Tag(Condition::parse(
+                &format!("{}={}", key, value),
+            )?)))),
+            None => Ok(Filter::Tag(Condition::parse(term)?)),
+        },
+        None => bail!("Unexpected end"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(condition: &str) -> Filter {
+        Filter::Tag(Condition::parse(condition).unwrap())
+    }
+
+    fn not(filter: Filter) -> Filter {
+        Filter::Not(Box::new(filter))
+    }
+
+    fn tags(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let filter: Filter = "a or b and c or d".parse().unwrap();
+        assert_eq!(
+            filter,
+            Filter::Or(vec![
+                tag("a"),
+                Filter::And(vec![tag("b"), tag("c")]),
+                tag("d")
+            ])
+        );
+        // Terms next to each other are joined with and
+        let filter: Filter = "a b=1 or c".parse().unwrap();
+        assert_eq!(
+            filter,
+            Filter::Or(vec![Filter::And(vec![tag("a"), tag("b=1")]), tag("c")])
+        );
+    }
+
+    #[test]
+    fn not_binds_tightest() {
+        let filter: Filter = "not a and not not b".parse().unwrap();
+        assert_eq!(filter, Filter::And(vec![not(tag("a")), not(not(tag("b")))]));
+    }
+
+    #[test]
+    fn parentheses_group() {
+        let filter: Filter = "(a or b) and not(c=1 or d)".parse().unwrap();
+        assert_eq!(
+            filter,
+            Filter::And(vec![
+                Filter::Or(vec![tag("a"), tag("b")]),
+                not(Filter::Or(vec![tag("c=1"), tag("d")])),
+            ])
+        );
+        assert_eq!("((a))".parse::<Filter>().unwrap(), tag("a"));
+    }
+
+    #[test]
+    fn not_equals_is_negated_equals() {
+        let filter: Filter = "highway!=footway".parse().unwrap();
+        assert_eq!(filter, not(tag("highway=footway")));
+        assert!(filter.matches(&tags(&[("highway", "primary")])));
+        // Elements without the key don't have it equal to anything either
+        assert!(filter.matches(&tags(&[])));
+        assert!(!filter.matches(&tags(&[("highway", "footway")])));
+    }
+
+    #[test]
+    fn matches_tags() {
+        let filter: Filter = "building or (natural=water and not water=pond)"
+            .parse()
+            .unwrap();
+        assert!(filter.matches(&tags(&[("building", "yes")])));
+        assert!(filter.matches(&tags(&[("natural", "water")])));
+        assert!(!filter.matches(&tags(&[("natural", "water"), ("water", "pond")])));
+        assert!(!filter.matches(&tags(&[("natural", "wood")])));
+    }
+
+    #[test]
+    fn rejects_malformed_filters() {
+        for (source, error) in [
+            ("", "Invalid filter ``"),
+            ("a or", "Invalid filter `a or`"),
+            ("(a", "Invalid filter `(a`"),
+            ("a)", "Invalid filter `a)`: Unexpected `)`"),
+            ("and a", "Invalid filter `and a`"),
+            ("=a", "Invalid filter `=a`"),
+        ] {
+            assert_eq!(source.parse::<Filter>().unwrap_err().to_string(), error);
+        }
+        // The context above the message says what's wrong
+        let error = "(a".parse::<Filter>().unwrap_err();
+        assert_eq!(error.root_cause().to_string(), "Missing `)`");
+    }
+}
@@ -0,0 +1,1018 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+};
+
+use glam::DVec2;
+
+use crate::{
+    osm::{MemberKind, Relation, Way, OSM},
+    profile::Profile,
+    projection::distance_meters,
+};
+
+/// Side length of the cells nodes are bucketed into for `closest_node`, in degrees
+const CELL_SIZE: f64 = 0.01;
+// How many rings of cells around the cursor are searched before giving up
+const MAX_SEARCH_RINGS: i32 = 10;
+
+/// Values of the `highway` tag that can be routed over, everything else (planned
+/// roads, platforms, race tracks, ...) is left out of the graph
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Highway {
+    Motorway,
+    MotorwayLink,
+    Trunk,
+    TrunkLink,
+    Primary,
+    PrimaryLink,
+    Secondary,
+    SecondaryLink,
+    Tertiary,
+    TertiaryLink,
+    Unclassified,
+    Residential,
+    LivingStreet,
+    Service,
+    Road,
+    Track,
+    Pedestrian,
+    Footway,
+    Path,
+    Cycleway,
+    Bridleway,
+    Steps,
+}
+
+impl Highway {
+    pub fn parse(value: &str) -> Option<Self> {
+        Some(match value {
+            "motorway" => Highway::Motorway,
+            "motorway_link" => Highway::MotorwayLink,
+            "trunk" => Highway::Trunk,
+            "trunk_link" => Highway::TrunkLink,
+            "primary" => Highway::Primary,
+            "primary_link" => Highway::PrimaryLink,
+            "secondary" => Highway::Secondary,
+            "secondary_link" => Highway::SecondaryLink,
+            "tertiary" => Highway::Tertiary,
+            "tertiary_link" => Highway::TertiaryLink,
+            "unclassified" => Highway::Unclassified,
+            "residential" => Highway::Residential,
+            "living_street" => Highway::LivingStreet,
+            "service" => Highway::Service,
+            "road" => Highway::Road,
+            "track" => Highway::Track,
+            "pedestrian" => Highway::Pedestrian,
+            "footway" => Highway::Footway,
+            "path" => Highway::Path,
+            "cycleway" => Highway::Cycleway,
+            "bridleway" => Highway::Bridleway,
+            "steps" => Highway::Steps,
+            _ => return None,
+        })
+    }
+}
+
+/// Rough groups of `surface` values, by how much they slow down wheels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Surface {
+    Paved,
+    Unpaved,
+    /// Cobbles, grass, sand and the like
+    Rough,
+}
+
+impl Surface {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "paved" | "asphalt" | "concrete" | "concrete:plates" | "concrete:lanes"
+            | "paving_stones" | "chipseal" | "metal" | "wood" => Some(Surface::Paved),
+            "unpaved" | "compacted" | "fine_gravel" | "gravel" | "ground" | "dirt" | "earth" => {
+                Some(Surface::Unpaved)
+            }
+            "sett" | "cobblestone" | "unhewn_cobblestone" | "pebblestone" | "grass" | "sand"
+            | "mud" | "rock" | "stone" => Some(Surface::Rough),
+            _ => None,
+        }
+    }
+}
+
+/// Value of an access tag like `access`, `motor_vehicle` or `foot`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessValue {
+    Yes,
+    Designated,
+    Destination,
+    Private,
+    No,
+}
+
+impl AccessValue {
+    // Rarer values like `customers` or `agricultural` count as private
+    fn parse(value: &str) -> Self {
+        match value {
+            "yes" | "permissive" => AccessValue::Yes,
+            "designated" | "official" => AccessValue::Designated,
+            "destination" | "delivery" => AccessValue::Destination,
+            "no" | "use_sidepath" | "discouraged" => AccessValue::No,
+            _ => AccessValue::Private,
+        }
+    }
+}
+
+/// Access tags of a way, from the most general to the most specific. Unset tags
+/// fall back to the more general ones and in the end to what the highway class allows.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Access {
+    pub access: Option<AccessValue>,
+    pub vehicle: Option<AccessValue>,
+    pub motor_vehicle: Option<AccessValue>,
+    pub motorcar: Option<AccessValue>,
+    pub bicycle: Option<AccessValue>,
+    pub foot: Option<AccessValue>,
+}
+
+impl Access {
+    fn from_way(way: &Way) -> Self {
+        let tag = |key| way.tag(key).map(AccessValue::parse);
+        Self {
+            access: tag("access"),
+            vehicle: tag("vehicle"),
+            motor_vehicle: tag("motor_vehicle"),
+            motorcar: tag("motorcar"),
+            bicycle: tag("bicycle"),
+            foot: tag("foot"),
+        }
+    }
+}
+
+/// One direction of a way segment between two neighbouring nodes
+#[derive(Clone, Copy, Debug)]
+pub struct Edge {
+    /// Graph node the edge leads to
+    pub to: usize,
+    /// OSM id of the way the segment is part of
+    pub way: i64,
+    pub length: f64,
+    pub highway: Highway,
+    /// Speed limit in km/h
+    pub maxspeed: Option<f32>,
+    pub access: Access,
+    pub surface: Option<Surface>,
+    /// Runs against the direction of a oneway. Vehicles can't take it, but it's kept
+    /// since oneways don't apply to pedestrians.
+    pub against_oneway: bool,
+    /// Cyclists may ride against the oneway, from `oneway:bicycle=no` or a contraflow
+    /// cycle lane
+    pub bicycle_contraflow: bool,
+}
+
+/// Rule from a `type=restriction` relation for turning off the `from` way at its via
+/// node. Only restrictions through a single via node are modelled, ones through a via
+/// way would need the search to remember more than the last edge.
+#[derive(Clone, Debug)]
+struct TurnRestriction {
+    profile: Profile,
+    /// `only_*` restrictions allow nothing but `to`, `no_*` ones forbid it
+    only: bool,
+    to: Vec<i64>,
+}
+
+impl TurnRestriction {
+    // A restriction from a way onto itself is a U-turn, going on along a way that
+    // passes through the via node isn't
+    fn matches(&self, from_way: i64, to_way: i64, reverses: bool) -> bool {
+        self.to.contains(&to_way) && (to_way != from_way || reverses)
+    }
+}
+
+/// Path found by `OSMGraph::plan_path_a_star`
+#[derive(Clone, Debug)]
+pub struct Route {
+    /// Graph nodes from start to end
+    pub nodes: Vec<usize>,
+    /// Length in meters
+    pub distance: f64,
+    /// Travel time in seconds
+    pub time: f64,
+}
+
+pub struct Node {
+    pub id: i64,
+    /// (lon, lat)
+    pub pos: DVec2,
+}
+
+/// Road network made of the routable `highway=*` ways of an `OSM`. Only nodes on
+/// those ways are part of it, with an edge for each direction of every segment.
+pub struct OSMGraph {
+    nodes: Vec<Node>,
+    edges: Vec<Vec<Edge>>,
+    /// Where the edges of each node start when they're numbered across all nodes
+    edge_offsets: Vec<usize>,
+    buckets: HashMap<(i32, i32), Vec<usize>>,
+    /// Turn restrictions by via node and the way they apply to turns from
+    restrictions: HashMap<(usize, i64), Vec<TurnRestriction>>,
+}
+
+impl OSMGraph {
+    pub fn from_osm(osm: &OSM) -> Self {
+        let mut nodes = Vec::new();
+        let mut edges: Vec<Vec<Edge>> = Vec::new();
+        let mut buckets = HashMap::new();
+        // Index into `nodes` for each node of the `OSM` that's on a road
+        let mut graph_index = HashMap::new();
+        let mut node = |osm_index: usize, edges: &mut Vec<Vec<Edge>>| {
+            *graph_index.entry(osm_index).or_insert_with(|| {
+                let osm_node = &osm.nodes()[osm_index];
+                buckets
+                    .entry(cell(osm_node.pos))
+                    .or_insert_with(Vec::new)
+                    .push(nodes.len());
+                nodes.push(Node {
+                    id: osm_node.id,
+                    pos: osm_node.pos,
+                });
+                edges.push(Vec::new());
+                nodes.len() - 1
+            })
+        };
+
+        for way in osm.ways() {
+            let Some(highway) = way.tag("highway").and_then(Highway::parse) else {
+                continue;
+            };
+            // Squares mapped as areas are walked across, not along their outline
+            if way.tag("area") == Some("yes") {
+                continue;
+            }
+            let maxspeed = way.tag("maxspeed").and_then(parse_maxspeed);
+            let access = Access::from_way(way);
+            let surface = way.tag("surface").and_then(Surface::parse);
+            let oneway = oneway(way, highway);
+            let bicycle_contraflow = way.tag("oneway:bicycle") == Some("no")
+                || way
+                    .tag("cycleway")
+                    .is_some_and(|lane| lane.starts_with("opposite"));
+            for pair in way.refs.windows(2) {
+                let (a, b) = (node(pair[0], &mut edges), node(pair[1], &mut edges));
+                let length = distance_meters(osm.nodes()[pair[0]].pos, osm.nodes()[pair[1]].pos);
+                let edge = |to, against_oneway| Edge {
+                    to,
+                    way: way.id,
+                    length,
+                    highway,
+                    maxspeed,
+                    access,
+                    surface,
+                    against_oneway,
+                    bicycle_contraflow,
+                };
+                let reversible = oneway == Some(Direction::Reversible);
+                edges[a].push(edge(b, reversible || oneway == Some(Direction::Backward)));
+                edges[b].push(edge(a, reversible || oneway == Some(Direction::Forward)));
+            }
+        }
+
+        let by_id: HashMap<_, _> = nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (node.id, index))
+            .collect();
+        let mut restrictions: HashMap<_, Vec<_>> = HashMap::new();
+        let mut via_ways = 0;
+        let mut off_road = 0;
+        for relation in osm.relations() {
+            if relation.tags.get("type").map(String::as_str) != Some("restriction") {
+                continue;
+            }
+            let ways = |role: &'static str| {
+                relation
+                    .members
+                    .iter()
+                    .filter(move |member| member.role == role && member.kind == MemberKind::Way)
+                    .map(|member| member.id)
+            };
+            let vias: Vec<_> = relation
+                .members
+                .iter()
+                .filter(|member| member.role == "via")
+                .collect();
+            let via = match vias[..] {
+                [via] if via.kind == MemberKind::Node => via,
+                _ => {
+                    via_ways += 1;
+                    continue;
+                }
+            };
+            // The via node isn't on a road, or was clipped away
+            let Some(&via) = by_id.get(&via.id) else {
+                off_road += 1;
+                continue;
+            };
+            let to: Vec<_> = ways("to").collect();
+            for profile in [Profile::Car, Profile::Bike, Profile::Foot] {
+                let Some(only) = restriction_kind(relation, profile) else {
+                    continue;
+                };
+                for from in ways("from") {
+                    restrictions
+                        .entry((via, from))
+                        .or_default()
+                        .push(TurnRestriction {
+                            profile,
+                            only,
+                            to: to.clone(),
+                        });
+                }
+            }
+        }
+        if via_ways > 0 {
+            eprintln!(
+                "Skipped {} turn restrictions without a single via node",
+                via_ways
+            );
+        }
+        if off_road > 0 {
+            eprintln!(
+                "Skipped {} turn restrictions whose via node isn't on a road",
+                off_road
+            );
+        }
+
+        let mut edge_offsets = Vec::with_capacity(edges.len() + 1);
+        let mut offset = 0;
+        for node_edges in &edges {
+            edge_offsets.push(offset);
+            offset += node_edges.len();
+        }
+        edge_offsets.push(offset);
+        Self {
+            nodes,
+            edges,
+            edge_offsets,
+            buckets,
+            restrictions,
+        }
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    /// Edges leaving `node`
+    pub fn edges(&self, node: usize) -> &[Edge] {
+        &self.edges[node]
+    }
+
+    /// Nearest node to a (lon, lat) position that `profile` can leave on a road,
+    /// searching outwards one ring of cells at a time
+    pub fn closest_node(&self, pos: DVec2, profile: Profile) -> Option<usize> {
+        let (x, y) = cell(pos);
+        let mut closest: Option<(f64, usize)> = None;
+        for ring in 0..=MAX_SEARCH_RINGS {
+            for cx in x - ring..=x + ring {
+                for cy in y - ring..=y + ring {
+                    if (cx - x).abs() != ring && (cy - y).abs() != ring {
+                        continue;
+                    }
+                    for &node in self.buckets.get(&(cx, cy)).into_iter().flatten() {
+                        if !self.edges[node]
+                            .iter()
+                            .any(|edge| profile.time(edge).is_some())
+                        {
+                            continue;
+                        }
+                        let distance = distance_meters(self.nodes[node].pos, pos);
+                        if closest.is_none_or(|(min, _)| distance < min) {
+                            closest = Some((distance, node));
+                        }
+                    }
+                }
+            }
+            // Anything in the next ring is at least this many degrees away across or
+            // along, and a degree across is shortest on the poleward edge of the search
+            let reach = ring as f64 * CELL_SIZE;
+            let lat = (pos.y.abs() + reach + CELL_SIZE).min(90.0);
+            let min_next = distance_meters(DVec2::new(0.0, lat), DVec2::new(reach, lat));
+            if closest.is_some_and(|(min, _)| min <= min_next) {
+                break;
+            }
+        }
+        closest.map(|(_, node)| node)
+    }
+
+    /// Fastest path from `a` to `b` for `profile`, only making turns its turn
+    /// restrictions allow
+    pub fn plan_path_a_star(&self, a: usize, b: usize, profile: Profile) -> Option<Route> {
+        let target = self.nodes[b].pos;
+        // Travel time for the straight line at the profile's top speed never overestimates
+        let heuristic =
+            |node: usize| distance_meters(self.nodes[node].pos, target) / profile.max_speed();
+        let start = self.start_state();
+        // Time, distance and previous state of every state reached so far. Only the
+        // states near the route are ever reached, so these stay small on short queries
+        let mut reached = HashMap::from([(start, (0.0, 0.0, start))]);
+        let mut queue = BinaryHeap::new();
+        queue.push(Queued {
+            estimate: heuristic(a),
+            node: start,
+        });
+        while let Some(Queued {
+            estimate,
+            node: state,
+        }) = queue.pop()
+        {
+            let (time, distance, _) = reached[&state];
+            let (node, moves) = self.moves(state, a, profile);
+            if node == b {
+                return Some(Route {
+                    nodes: self.path(&reached, a, state),
+                    distance,
+                    time,
+                });
+            }
+            // Already reached more quickly since this entry was queued
+            if estimate > time + heuristic(node) {
+                continue;
+            }
+            for (next, edge, edge_time) in moves {
+                let next_time = time + edge_time;
+                if reached
+                    .get(&next)
+                    .is_none_or(|&(known, _, _)| next_time < known)
+                {
+                    reached.insert(next, (next_time, distance + edge.length, state));
+                    queue.push(Queued {
+                        estimate: next_time + heuristic(edge.to),
+                        node: next,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// Fastest travel time from `a` to every node `profile` can reach within `limit`
+    /// seconds, searching outwards like `plan_path_a_star` without a target
+    pub fn travel_times(&self, a: usize, profile: Profile, limit: f64) -> HashMap<usize, f64> {
+        let start = self.start_state();
+        let mut state_times = HashMap::from([(start, 0.0)]);
+        let mut node_times = HashMap::new();
+        let mut queue = BinaryHeap::from([Queued {
+            estimate: 0.0,
+            node: start,
+        }]);
+        while let Some(Queued {
+            estimate: time,
+            node: state,
+        }) = queue.pop()
+        {
+            if time > state_times[&state] {
+                continue;
+            }
+            let (node, moves) = self.moves(state, a, profile);
+            // Nodes are first settled by their fastest edge
+            node_times.entry(node).or_insert(time);
+            for (next, _, edge_time) in moves {
+                let next_time = time + edge_time;
+                if next_time <= limit && state_times.get(&next).is_none_or(|&t| next_time < t) {
+                    state_times.insert(next, next_time);
+                    queue.push(Queued {
+                        estimate: next_time,
+                        node: next,
+                    });
+                }
+            }
+        }
+        node_times
+    }
+
+    /// Whether every turn along `route` is allowed for `profile`. Between two nodes the
+    /// fastest edge is assumed to be taken.
+    #[cfg(test)]
+    pub fn allows_route(&self, route: &Route, profile: Profile) -> bool {
+        let fastest = |a: usize, b: usize| {
+            self.edges[a]
+                .iter()
+                .filter(|edge| edge.to == b)
+                .filter_map(|edge| Some((profile.time(edge)?, edge)))
+                .min_by(|x, y| x.0.total_cmp(&y.0))
+                .map(|(_, edge)| edge)
+        };
+        route.nodes.windows(3).all(|turn| {
+            match (fastest(turn[0], turn[1]), fastest(turn[1], turn[2])) {
+                (Some(arrived_by), Some(next)) => {
+                    self.allows_turn(turn[0], arrived_by, turn[1], next, profile)
+                }
+                _ => false,
+            }
+        })
+    }
+
+    // Whether `profile` may go on with `next` after reaching `via` from `from` over
+    // `arrived_by`. Turning back is only allowed at dead ends, otherwise it would be a
+    // way around every restriction.
+    fn allows_turn(
+        &self,
+        from: usize,
+        arrived_by: &Edge,
+        via: usize,
+        next: &Edge,
+        profile: Profile,
+    ) -> bool {
+        if profile.restriction_modes().is_empty() {
+            return true;
+        }
+        let reverses = next.to == from;
+        if reverses
+            && self.edges[via]
+                .iter()
+                .any(|edge| edge.to != from && profile.time(edge).is_some())
+        {
+            return false;
+        }
+        self.restrictions
+            .get(&(via, arrived_by.way))
+            .into_iter()
+            .flatten()
+            .filter(|restriction| restriction.profile == profile)
+            .all(|restriction| {
+                restriction.matches(arrived_by.way, next.way, reverses) == restriction.only
+            })
+    }
+
+    /// Whether a turn is allowed depends on the edge it's made from, so searches go
+    /// over edges instead of nodes. The start isn't reached by an edge and gets the
+    /// index after the last one, which is also the number of edges.
+    pub(crate) fn start_state(&self) -> usize {
+        self.edge_offsets[self.nodes.len()]
+    }
+
+    /// The node a search state is at when `a` is the start, with the edges `profile`
+    /// can go on with by their index across all nodes and their travel time
+    pub(crate) fn moves(
+        &self,
+        state: usize,
+        a: usize,
+        profile: Profile,
+    ) -> (usize, impl Iterator<Item = (usize, &Edge, f64)> + '_) {
+        let arrival = (state != self.start_state()).then(|| self.edge_by_index(state));
+        let node = arrival.map_or(a, |(_, edge)| edge.to);
+        let moves = self.edges[node]
+            .iter()
+            .enumerate()
+            .filter_map(move |(i, edge)| {
+                let time = profile.time(edge)?;
+                let allowed = arrival.is_none_or(|(from, arrived_by)| {
+                    self.allows_turn(from, arrived_by, node, edge, profile)
+                });
+                allowed.then_some((self.edge_offsets[node] + i, edge, time))
+            });
+        (node, moves)
+    }
+
+    /// Indices across all nodes of the edges arriving at `node`. Every segment has an
+    /// edge both ways, so they're found among the neighbours' edges.
+    pub(crate) fn arrivals(&self, node: usize) -> Vec<usize> {
+        let mut arrivals: Vec<_> = self.edges[node]
+            .iter()
+            .flat_map(|edge| {
+                let back = self.edges[edge.to].iter().enumerate();
+                back.filter(|(_, back)| back.to == node)
+                    .map(|(i, _)| self.edge_offsets[edge.to] + i)
+            })
+            .collect();
+        arrivals.sort_unstable();
+        arrivals.dedup();
+        arrivals
+    }
+
+    /// The node an edge leaves from and the edge, by its index across all nodes
+    pub(crate) fn edge_by_index(&self, index: usize) -> (usize, &Edge) {
+        let from = self.edge_offsets.partition_point(|&offset| offset <= index) - 1;
+        (from, &self.edges[from][index - self.edge_offsets[from]])
+    }
+
+    // Follows the previous states in `reached` back from the edge `last` to the start,
+    // which is its own predecessor
+    fn path(
+        &self,
+        reached: &HashMap<usize, (f64, f64, usize)>,
+        a: usize,
+        last: usize,
+    ) -> Vec<usize> {
+        let mut path = Vec::new();
+        let mut state = last;
+        while reached[&state].2 != state {
+            path.push(self.edge_by_index(state).1.to);
+            state = reached[&state].2;
+        }
+        path.push(a);
+        path.reverse();
+        path
+    }
+}
+
+fn cell(pos: DVec2) -> (i32, i32) {
+    let cell = (pos / CELL_SIZE).floor();
+    (cell.x as i32, cell.y as i32)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Backward,
+    /// Switches direction during the day, so vehicles can't count on either
+    Reversible,
+}
+
+// Motorways and roundabouts are oneway unless tagged otherwise
+fn oneway(way: &Way, highway: Highway) -> Option<Direction> {
+    match way.tag("oneway") {
+        Some("yes" | "true" | "1") => Some(Direction::Forward),
+        Some("-1" | "reverse") => Some(Direction::Backward),
+        Some("reversible" | "alternating") => Some(Direction::Reversible),
+        Some(_) => None,
+        None if highway == Highway::Motorway
+            || matches!(way.tag("junction"), Some("roundabout" | "circular")) =>
+        {
+            Some(Direction::Forward)
+        }
+        None => None,
+    }
+}
+
+/// Whether a `type=restriction` relation is an `only_*` restriction for `profile`, or
+/// `None` if it doesn't apply to it. `restriction:<mode>` takes precedence over the
+/// plain `restriction`, which modes listed in `except` are exempt from.
+fn restriction_kind(relation: &Relation, profile: Profile) -> Option<bool> {
+    let modes = profile.restriction_modes();
+    let tag = |key: &str| relation.tags.get(key).map(String::as_str);
+    let value = match modes
+        .iter()
+        .find_map(|mode| tag(&format!("restriction:{}", mode)))
+    {
+        Some(value) => value,
+        None => {
+            let exempt = tag("except")
+                .is_some_and(|except| except.split(';').any(|mode| modes.contains(&mode.trim())));
+            if modes.is_empty() || exempt {
+                return None;
+            }
+            tag("restriction")?
+        }
+    };
+    if value.starts_with("only_") {
+        Some(true)
+    } else if value.starts_with("no_") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// `maxspeed` in km/h, from a plain number, `<number> mph` or `none`. Zone values
+/// like `DE:urban` are left to the routing profile.
+fn parse_maxspeed(value: &str) -> Option<f32> {
+    let value = value.trim();
+    if value == "none" {
+        return Some(f32::INFINITY);
+    }
+    match value.strip_suffix("mph") {
+        Some(mph) => mph.trim().parse::<f32>().ok().map(|mph| mph * 1.609_344),
+        None => value.parse().ok(),
+    }
+}
+
+/// Min-heap entry for shortest path searches, ordered by `estimate`
+#[derive(PartialEq)]
+pub struct Queued {
+    pub estimate: f64,
+    pub node: usize,
+}
+
+impl Eq for Queued {}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.estimate.total_cmp(&self.estimate)
+    }
+}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hierarchy::ContractionHierarchy, osm::LoadOptions};
+
+    // A crossroads at node 1 with arms to the south (2), west (3), north (4) and east
+    // (5), and a detour from the north arm round to the west one through node 6
+    const CROSSROADS: &str = r#"
+        <node id="1" lat="0.0" lon="0.0"/>
+        <node id="2" lat="-0.001" lon="0.0"/>
+        <node id="3" lat="0.0" lon="-0.001"/>
+        <node id="4" lat="0.001" lon="0.0"/>
+        <node id="5" lat="0.0" lon="0.001"/>
+        <node id="6" lat="0.001" lon="-0.001"/>
+        <way id="10"><nd ref="2"/><nd ref="1"/><tag k="highway" v="residential"/></way>
+        <way id="11"><nd ref="1"/><nd ref="3"/><tag k="highway" v="residential"/></way>
+        <way id="12"><nd ref="1"/><nd ref="4"/><tag k="highway" v="residential"/></way>
+        <way id="13"><nd ref="1"/><nd ref="5"/><tag k="highway" v="residential"/></way>
+        <way id="14"><nd ref="4"/><nd ref="6"/><nd ref="3"/><tag k="highway" v="residential"/></way>
+    "#;
+
+    // Builds the graph of `CROSSROADS` plus `extra` elements
+    fn graph(extra: &str) -> OSMGraph {
+        let xml = format!("<osm version=\"0.6\">{}{}</osm>", CROSSROADS, extra);
+        OSMGraph::from_osm(&OSM::from_xml(&xml, &LoadOptions::default()).unwrap())
+    }
+
+    // OSM ids of the nodes along the route from `a` to `b`
+    fn route(graph: &OSMGraph, a: i64, b: i64, profile: Profile) -> Option<Vec<i64>> {
+        let index = |id| graph.nodes().iter().position(|node| node.id == id).unwrap();
+        let route = graph.plan_path_a_star(index(a), index(b), profile)?;
+        Some(
+            route
+                .nodes
+                .iter()
+                .map(|&node| graph.nodes[node].id)
+                .collect(),
+        )
+    }
+
+    fn restriction(tags: &str, from: i64, to: i64) -> String {
+        format!(
+            r#"<relation id="100">
+                <member type="way" ref="{}" role="from"/>
+                <member type="node" ref="1" role="via"/>
+                <member type="way" ref="{}" role="to"/>
+                <tag k="type" v="restriction"/>{}
+            </relation>"#,
+            from, to, tags
+        )
+    }
+
+    #[test]
+    fn unrestricted_turn() {
+        let graph = graph("");
+        assert_eq!(route(&graph, 2, 3, Profile::Car), Some(vec![2, 1, 3]));
+    }
+
+    #[test]
+    fn no_left_turn_takes_the_detour() {
+        let graph = graph(&restriction(
+            r#"<tag k="restriction" v="no_left_turn"/>"#,
+            10,
+            11,
+        ));
+        assert_eq!(route(&graph, 2, 3, Profile::Car), Some(vec![2, 1, 4, 6, 3]));
+        // Only turns off the from way are restricted
+        assert_eq!(route(&graph, 3, 2, Profile::Car), Some(vec![3, 1, 2]));
+        // Pedestrians don't follow turn restrictions
+        assert_eq!(route(&graph, 2, 3, Profile::Foot), Some(vec![2, 1, 3]));
+    }
+
+    #[test]
+    fn only_straight_on_comes_back_through_the_via_node() {
+        let graph = graph(&restriction(
+            r#"<tag k="restriction" v="only_straight_on"/>"#,
+            10,
+            12,
+        ));
+        assert_eq!(
+            route(&graph, 2, 5, Profile::Car),
+            Some(vec![2, 1, 4, 6, 3, 1, 5])
+        );
+    }
+
+    #[test]
+    fn no_u_turn_at_a_dead_end() {
+        // Node 5 is a dead end, so turning around there is allowed unless restricted
+        let graph = graph(
+            r#"<relation id="100">
+                <member type="way" ref="13" role="from"/>
+                <member type="node" ref="5" role="via"/>
+                <member type="way" ref="13" role="to"/>
+                <tag k="type" v="restriction"/>
+                <tag k="restriction" v="no_u_turn"/>
+            </relation>"#,
+        );
+        let index = |id| graph.nodes().iter().position(|node| node.id == id).unwrap();
+        let turn_back = Route {
+            nodes: vec![index(1), index(5), index(1)],
+            distance: 0.0,
+            time: 0.0,
+        };
+        assert!(!graph.allows_route(&turn_back, Profile::Car));
+        assert!(graph.allows_route(&turn_back, Profile::Foot));
+        assert_eq!(route(&graph, 5, 2, Profile::Car), Some(vec![5, 1, 2]));
+    }
+
+    #[test]
+    fn u_turns_only_at_dead_ends() {
+        let graph = graph(&restriction(
+            r#"<tag k="restriction" v="no_left_turn"/>"#,
+            10,
+            11,
+        ));
+        let index = |id| graph.nodes().iter().position(|node| node.id == id).unwrap();
+        let turn_back = |via| Route {
+            nodes: vec![index(1), index(via), index(1)],
+            distance: 0.0,
+            time: 0.0,
+        };
+        assert!(!graph.allows_route(&turn_back(4), Profile::Car));
+        assert!(graph.allows_route(&turn_back(5), Profile::Car));
+    }
+
+    #[test]
+    fn travel_times_stop_at_the_limit() {
+        let graph = graph("");
+        let index = |id| graph.nodes().iter().position(|node| node.id == id).unwrap();
+        // About 111 m to the crossroads at 30 km/h
+        let times = graph.travel_times(index(2), Profile::Car, 20.0);
+        assert_eq!(times.len(), 2);
+        assert_eq!(times[&index(2)], 0.0);
+        assert!((times[&index(1)] - 13.3).abs() < 0.1);
+
+        let restricted = self::graph(&restriction(
+            r#"<tag k="restriction" v="no_left_turn"/>"#,
+            10,
+            11,
+        ));
+        let index = |id| {
+            restricted
+                .nodes()
+                .iter()
+                .position(|node| node.id == id)
+                .unwrap()
+        };
+        let times = restricted.travel_times(index(2), Profile::Car, 120.0);
+        // Round the detour instead of turning left
+        assert!((times[&index(3)] - 13.3 * 4.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn except_and_mode_specific_restrictions() {
+        let except = graph(&restriction(
+            r#"<tag k="restriction" v="no_left_turn"/><tag k="except" v="psv;bicycle"/>"#,
+            10,
+            11,
+        ));
+        assert_eq!(route(&except, 2, 3, Profile::Bike), Some(vec![2, 1, 3]));
+        assert_eq!(
+            route(&except, 2, 3, Profile::Car),
+            Some(vec![2, 1, 4, 6, 3])
+        );
+
+        let bicycle = graph(&restriction(
+            r#"<tag k="restriction:bicycle" v="no_left_turn"/>"#,
+            10,
+            11,
+        ));
+        assert_eq!(
+            route(&bicycle, 2, 3, Profile::Bike),
+            Some(vec![2, 1, 4, 6, 3])
+        );
+        assert_eq!(route(&bicycle, 2, 3, Profile::Car), Some(vec![2, 1, 3]));
+    }
+
+    // A single way from node 1 to node 2 with `tags`
+    fn one_way(tags: &str) -> OSMGraph {
+        let xml = format!(
+            r#"<osm version="0.6">
+                <node id="1" lat="0.0" lon="0.0"/>
+                <node id="2" lat="0.0" lon="0.001"/>
+                <way id="10"><nd ref="1"/><nd ref="2"/>{}</way>
+            </osm>"#,
+            tags
+        );
+        OSMGraph::from_osm(&OSM::from_xml(&xml, &LoadOptions::default()).unwrap())
+    }
+
+    #[test]
+    fn oneway_directions() {
+        let backward = one_way(r#"<tag k="highway" v="residential"/><tag k="oneway" v="-1"/>"#);
+        assert_eq!(route(&backward, 2, 1, Profile::Car), Some(vec![2, 1]));
+        assert_eq!(route(&backward, 1, 2, Profile::Car), None);
+        assert_eq!(route(&backward, 1, 2, Profile::Bike), None);
+        assert_eq!(route(&backward, 1, 2, Profile::Foot), Some(vec![1, 2]));
+
+        let reversible =
+            one_way(r#"<tag k="highway" v="residential"/><tag k="oneway" v="reversible"/>"#);
+        assert_eq!(route(&reversible, 1, 2, Profile::Car), None);
+        assert_eq!(route(&reversible, 2, 1, Profile::Car), None);
+        assert_eq!(route(&reversible, 1, 2, Profile::Foot), Some(vec![1, 2]));
+        assert_eq!(route(&reversible, 2, 1, Profile::Foot), Some(vec![2, 1]));
+
+        let roundabout =
+            one_way(r#"<tag k="highway" v="residential"/><tag k="junction" v="roundabout"/>"#);
+        assert_eq!(route(&roundabout, 1, 2, Profile::Car), Some(vec![1, 2]));
+        assert_eq!(route(&roundabout, 2, 1, Profile::Car), None);
+    }
+
+    #[test]
+    fn parses_maxspeed_units() {
+        let mph = parse_maxspeed("30 mph").unwrap();
+        assert!((mph - 48.28).abs() < 0.01);
+        assert_eq!(parse_maxspeed("30mph"), Some(mph));
+        assert_eq!(parse_maxspeed(" 50 "), Some(50.0));
+        assert_eq!(parse_maxspeed("none"), Some(f32::INFINITY));
+        assert_eq!(parse_maxspeed("walk"), None);
+        assert_eq!(parse_maxspeed("mph"), None);
+
+        let graph = one_way(r#"<tag k="highway" v="primary"/><tag k="maxspeed" v="30 mph"/>"#);
+        assert_eq!(graph.edges(0)[0].maxspeed, Some(mph));
+    }
+
+    #[test]
+    fn leaves_out_areas_and_other_ways() {
+        let square = one_way(r#"<tag k="highway" v="pedestrian"/><tag k="area" v="yes"/>"#);
+        assert!(square.nodes().is_empty());
+        let river = one_way(r#"<tag k="waterway" v="river"/>"#);
+        assert!(river.nodes().is_empty());
+        let street = one_way(r#"<tag k="highway" v="pedestrian"/><tag k="area" v="no"/>"#);
+        assert_eq!(street.nodes().len(), 2);
+    }
+
+    #[test]
+    fn snaps_to_the_nearest_routable_node() {
+        let graph = graph(
+            r#"<node id="7" lat="0.0" lon="0.003"/>
+            <way id="15"><nd ref="5"/><nd ref="7"/><tag k="highway" v="footway"/></way>"#,
+        );
+        let closest = |pos, profile| {
+            let node = graph.closest_node(pos, profile)?;
+            Some(graph.nodes()[node].id)
+        };
+        let click = DVec2::new(0.0029, 0.0);
+        assert_eq!(closest(click, Profile::Foot), Some(7));
+        assert_eq!(closest(click, Profile::Car), Some(5));
+
+        // At 60° north a degree east is half as long as a degree north, so node 1 is
+        // closer than node 3 although it's more degrees away
+        let xml = r#"<osm version="0.6">
+            <node id="1" lat="60.0" lon="10.0015"/>
+            <node id="2" lat="60.0" lon="10.003"/>
+            <node id="3" lat="60.001" lon="10.0"/>
+            <node id="4" lat="60.002" lon="10.0"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+            <way id="11"><nd ref="3"/><nd ref="4"/><tag k="highway" v="residential"/></way>
+        </osm>"#;
+        let graph = OSMGraph::from_osm(&OSM::from_xml(xml, &LoadOptions::default()).unwrap());
+        let node = graph.closest_node(DVec2::new(10.0, 60.0), Profile::Car);
+        assert_eq!(node.map(|node| graph.nodes()[node].id), Some(1));
+    }
+
+    #[test]
+    fn hierarchy_routes_as_fast_as_a_star() {
+        let fixtures = [
+            // A fast oneway gives routes that differ by direction
+            r#"<way id="15"><nd ref="5"/><nd ref="6"/>
+                <tag k="highway" v="primary"/><tag k="oneway" v="yes"/></way>"#
+                .to_owned(),
+            restriction(r#"<tag k="restriction" v="no_left_turn"/>"#, 10, 11),
+            restriction(r#"<tag k="restriction" v="only_straight_on"/>"#, 10, 12),
+            restriction(
+                r#"<tag k="restriction" v="no_left_turn"/><tag k="except" v="bicycle"/>"#,
+                10,
+                11,
+            ),
+            r#"<relation id="100">
+                <member type="way" ref="13" role="from"/>
+                <member type="node" ref="5" role="via"/>
+                <member type="way" ref="13" role="to"/>
+                <tag k="type" v="restriction"/>
+                <tag k="restriction" v="no_u_turn"/>
+            </relation>"#
+                .to_owned(),
+        ];
+        for fixture in fixtures {
+            let graph = graph(&fixture);
+            for profile in [Profile::Car, Profile::Bike, Profile::Foot] {
+                let hierarchy = ContractionHierarchy::build(&graph, profile);
+                for a in 0..graph.nodes().len() {
+                    for b in 0..graph.nodes().len() {
+                        let expected = graph.plan_path_a_star(a, b, profile);
+                        let route = hierarchy.route(&graph, a, b);
+                        assert_eq!(route.is_some(), expected.is_some());
+                        let (Some(route), Some(expected)) = (route, expected) else {
+                            continue;
+                        };
+                        assert_eq!((route.nodes[0], route.nodes.last()), (a, Some(&b)));
+                        assert!((route.time - expected.time).abs() < 1e-9);
+                        assert!((route.distance - expected.distance).abs() < 1e-6);
+                        assert!(graph.allows_route(&route, profile));
+                    }
+                }
+            }
+        }
+    }
+}
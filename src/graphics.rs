@@ -2,7 +2,7 @@ use anyhow::{bail, Result};
 use bytemuck::Zeroable;
 use glam::Vec2;
 use smaa::SmaaTarget;
-use std::sync::Arc;
+use std::{ops::Range, sync::Arc};
 use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
     BindGroupDescriptor, BindGroupLayoutDescriptor, BindGroupLayoutEntry, Device, DeviceDescriptor,
@@ -11,22 +11,53 @@ use wgpu::{
     RequestAdapterOptions, ShaderModuleDescriptor, Surface, SurfaceConfiguration,
     TextureViewDescriptor,
 };
-use winit::{dpi::PhysicalSize, event::Event, window::Window};
+use winit::{
+    dpi::PhysicalSize,
+    event::{ElementState, Event, KeyEvent, WindowEvent},
+    keyboard::Key,
+    window::Window,
+};
 
 use crate::{
-    camera::{Camera, CameraController},
+    camera::{split_f64, CameraController},
+    lod::Lod,
+    style::GpuStyle,
     vertex::Vertex,
+    Batch, RawRenderData,
 };
 
+// Lines are extruded on the GPU, so keep tiles whose ways could reach into view
+const CULL_MARGIN_PIXELS: f32 = 64.0;
+const VERTEX_SIZE: u64 = std::mem::size_of::<Vertex>() as u64;
+const INDEX_SIZE: u64 = std::mem::size_of::<u32>() as u64;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Uniforms {
-    // _padding: [u8; 8],
-    // aspect: f32,
-    transform: glam::Mat4,
-    aspect: f32,
-    _padding: [f32; 7]
-    // color: glam::Vec4,
+    // Camera center split like the vertex positions, see `split_f64`
+    center_high: Vec2,
+    center_low: Vec2,
+    scale: Vec2,
+    screen_size: Vec2,
+    zoom_level: f32,
+    pixels_per_meter: f32,
+    _padding: [f32; 2],
+}
+
+/// Batches drawn with the same windows into the vertex and line index buffers. The
+/// line shader reads both as storage buffers, which can't be bound past the adapter's
+/// `max_storage_buffer_binding_size`, so large maps are split into chunks that fit.
+#[derive(Clone, Debug, PartialEq)]
+struct Chunk {
+    vertices: Range<u32>,
+    lines: Range<u32>,
+}
+
+/// A chunk's bind group and the first line index its window covers
+struct BoundChunk {
+    chunk: Chunk,
+    bind_group: wgpu::BindGroup,
+    first_index: u32,
 }
 
 pub struct Graphics {
@@ -36,20 +67,42 @@ pub struct Graphics {
     config: SurfaceConfiguration,
     size: PhysicalSize<u32>,
     window: Arc<Window>,
-    vertex_buffer: wgpu::Buffer,
     uniform_buffer: wgpu::Buffer,
-    index_buffer: wgpu::Buffer,
-    num_indices: u32,
-    uniform_bind_group: wgpu::BindGroup,
+    /// Bound with a chunk that starts at the beginning of the buffers
+    no_chunk_buffer: wgpu::Buffer,
+    vertex_buffer: wgpu::Buffer,
+    fill_index_buffer: wgpu::Buffer,
+    lods: Vec<Lod>,
+    lod: usize,
+    /// Sorted by their vertices, which don't overlap
+    chunks: Vec<BoundChunk>,
+    /// Number of files drawn as separate layers, 1 when they're merged
+    layers: u32,
+    /// Bit per layer toggled off with the number keys
+    hidden_layers: u32,
+    visible_min: Vec2,
+    visible_max: Vec2,
     line_pipeline: RenderPipeline,
-    point_pipeline: RenderPipeline,
-    point_buffer: wgpu::Buffer,
-    point_count: u32,
+    fill_pipeline: RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    overlay: Option<Overlay>,
     smaa_target: SmaaTarget,
 }
 
+/// Lines, markers and areas drawn over the map, like a planned route. They have their
+/// own vertices, indices and styles, bound in place of the map's.
+struct Overlay {
+    bind_group: wgpu::BindGroup,
+    segments: u32,
+    vertex_buffer: wgpu::Buffer,
+    fill_index_buffer: Option<wgpu::Buffer>,
+    fill_indices: u32,
+}
+
 impl Graphics {
-    pub async fn new(window: Window, vertex_data: &[Vertex], indices: &[u32]) -> Result<Self> {
+    pub async fn new(window: Window, render_data: &RawRenderData) -> Result<Self> {
+        let vertex_data = &render_data.vertices;
+        let indices = &render_data.indices;
         let window = Arc::new(window);
 
         let instance = Instance::new(InstanceDescriptor {
@@ -76,6 +129,10 @@ impl Graphics {
                     required_features: Features::empty(),
                     required_limits: Limits {
                         max_buffer_size: 786_432_000,
+                        // The line shader reads the vertex and index buffers as storage
+                        max_storage_buffer_binding_size: adapter
+                            .limits()
+                            .max_storage_buffer_binding_size,
                         ..Default::default()
                     },
                     ..Default::default()
@@ -86,51 +143,161 @@ impl Graphics {
         surface.configure(&device, &config);
         let vertex_buffer = device.create_buffer_init(&BufferInitDescriptor {
             label: None,
-            contents: bytemuck::cast_slice(&vertex_data),
-            usage: wgpu::BufferUsages::VERTEX,
+            contents: bytemuck::cast_slice(vertex_data),
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE,
         });
 
+        // Bindings can't be empty, a lone restart index draws nothing
+        let indices = if indices.is_empty() {
+            &[u32::MAX][..]
+        } else {
+            indices
+        };
         let index_buffer = device.create_buffer_init(&BufferInitDescriptor {
             label: None,
             contents: bytemuck::cast_slice(indices),
-            usage: wgpu::BufferUsages::INDEX,
+            usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::STORAGE,
         });
 
-        let max_highlighted = vertex_data.len() as u64;
-        let point_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        let fill_index_buffer = device.create_buffer_init(&BufferInitDescriptor {
             label: None,
-            size: max_highlighted,
-            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
+            contents: bytemuck::cast_slice(&render_data.fill_indices),
+            usage: wgpu::BufferUsages::INDEX,
         });
 
-        let num_indices = indices.len() as u32;
-
         let uniform_buffer = device.create_buffer_init(&BufferInitDescriptor {
             label: None,
             contents: bytemuck::cast_slice(&[Uniforms::zeroed()]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
+        let style_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&render_data.styles),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
         let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
             label: None,
-            entries: &[BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::VERTEX,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
                 },
-                count: None,
-            }],
+                BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
         });
-        let uniform_bind_group = device.create_bind_group(&BindGroupDescriptor {
+        let limits = device.limits();
+        let alignment = limits.min_storage_buffer_offset_alignment as u64;
+        let chunks: Vec<_> = chunks(
+            &render_data.lods,
+            limits.max_storage_buffer_binding_size as u64,
+            alignment,
+        )
+        .into_iter()
+        .map(|chunk| {
+            let window = |range: &Range<u32>, size: u64| {
+                let start = range.start as u64 * size / alignment * alignment;
+                (start, range.end as u64 * size - start)
+            };
+            let (vertex_start, vertex_size) = window(&chunk.vertices, VERTEX_SIZE);
+            let (index_start, index_size) = window(&chunk.lines, INDEX_SIZE);
+            // The word the vertex window starts at, padded to a uniform's minimum size
+            let chunk_buffer = device.create_buffer_init(&BufferInitDescriptor {
+                label: Some("Chunk"),
+                contents: bytemuck::cast_slice(&[(vertex_start / 4) as u32, 0, 0, 0]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+            let binding = |buffer, offset, size| {
+                wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer,
+                    offset,
+                    size: wgpu::BufferSize::new(size),
+                })
+            };
+            let bind_group = device.create_bind_group(&BindGroupDescriptor {
+                label: Some("Chunk"),
+                layout: &bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: uniform_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: style_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: binding(&vertex_buffer, vertex_start, vertex_size),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: binding(&index_buffer, index_start, index_size),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: chunk_buffer.as_entire_binding(),
+                    },
+                ],
+            });
+            BoundChunk {
+                chunk,
+                bind_group,
+                first_index: (index_start / INDEX_SIZE) as u32,
+            }
+        })
+        .collect();
+        if chunks.len() > 1 {
+            eprintln!("Drawing lines in {} chunks", chunks.len());
+        }
+        let no_chunk_buffer = device.create_buffer_init(&BufferInitDescriptor {
             label: None,
-            layout: &bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: uniform_buffer.as_entire_binding(),
-            }],
+            contents: bytemuck::cast_slice(&[0u32; 4]),
+            usage: wgpu::BufferUsages::UNIFORM,
         });
 
         let line_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
@@ -139,10 +306,8 @@ impl Graphics {
             push_constant_ranges: &[],
         });
 
-        let line_pipeline =
-            make_pipeline(&device, &config, &line_layout, PrimitiveTopology::LineStrip);
-        let point_pipeline =
-            make_pipeline(&device, &config, &line_layout, PrimitiveTopology::PointList);
+        let line_pipeline = make_line_pipeline(&device, &config, &line_layout);
+        let fill_pipeline = make_fill_pipeline(&device, &config, &line_layout);
 
         let smaa_target = SmaaTarget::new(
             &device,
@@ -159,16 +324,28 @@ impl Graphics {
             surface,
             window,
             line_pipeline,
-            point_pipeline,
-            point_buffer,
-            config,
+            fill_pipeline,
             vertex_buffer,
+            fill_index_buffer,
+            lods: render_data.lods.clone(),
+            lod: 0,
+            layers: render_data
+                .lods
+                .iter()
+                .flat_map(|lod| &lod.batches)
+                .map(|batch| batch.layer + 1)
+                .max()
+                .unwrap_or(1),
+            hidden_layers: 0,
+            visible_min: Vec2::splat(f32::MIN),
+            visible_max: Vec2::splat(f32::MAX),
+            config,
             uniform_buffer,
-            index_buffer,
-            num_indices,
-            uniform_bind_group,
+            no_chunk_buffer,
+            chunks,
             size,
-            point_count: 0,
+            bind_group_layout,
+            overlay: None,
             smaa_target,
         })
     }
@@ -186,28 +363,105 @@ impl Graphics {
     }
 
     pub fn update(&mut self, uniforms: &CameraController) {
+        let camera = &uniforms.camera;
+        let (center_high, center_low) = split_f64(camera.center);
+        let (visible_min, visible_max) = camera.visible_bounds(self.size_vec(), CULL_MARGIN_PIXELS);
+        self.visible_min = visible_min.as_vec2();
+        self.visible_max = visible_max.as_vec2();
+        let zoom_level = camera.zoom_level(self.size.width as f32);
+        self.lod = self
+            .lods
+            .iter()
+            .position(|lod| lod.contains(zoom_level))
+            .unwrap_or(0);
         self.queue.write_buffer(
             &self.uniform_buffer,
             0,
             bytemuck::cast_slice(&[Uniforms {
-                transform: uniforms.camera.matrix(),
-                aspect: self.size.width as f32 / self.size.height as f32,
-                _padding: [0.0; 7],
+                center_high,
+                center_low,
+                scale: camera.scale(self.size_vec()).as_vec2(),
+                screen_size: self.size_vec(),
+                zoom_level,
+                pixels_per_meter: camera.pixels_per_meter(self.size_vec()),
+                _padding: [0.0; 2],
             }]),
         );
-        // let v_bytes = (vertices.len() * std::mem::size_of::<Vertex>()) as u64;
-        // if v_bytes > self.point_buffer.size() {
-        //     println!("Resizing point buffer");
-        //     self.point_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
-        //         label: None,
-        //         size: v_bytes,
-        //         usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-        //         mapped_at_creation: false,
-        //     });
-        // }
-        // self.queue
-        //     .write_buffer(&self.point_buffer, 0, bytemuck::cast_slice(vertices));
-        // self.point_count = vertices.len() as u32;
+    }
+
+    /// Replaces what's drawn over the map. `indices` are line strips separated by
+    /// `u32::MAX` like the map's, a strip of one vertex repeated draws a dot.
+    /// `fill_indices` are triangles filled with their style's fill, drawn below the lines.
+    pub fn set_overlay(
+        &mut self,
+        vertices: &[Vertex],
+        indices: &[u32],
+        fill_indices: &[u32],
+        styles: &[GpuStyle],
+    ) {
+        if indices.len() < 2 && fill_indices.is_empty() {
+            self.overlay = None;
+            return;
+        }
+        let buffer = |contents: &[u8], usage| {
+            self.device.create_buffer_init(&BufferInitDescriptor {
+                label: Some("Overlay"),
+                contents,
+                usage,
+            })
+        };
+        let storage = wgpu::BufferUsages::STORAGE;
+        let styles = buffer(bytemuck::cast_slice(styles), storage);
+        let vertex_buffer = buffer(
+            bytemuck::cast_slice(vertices),
+            storage | wgpu::BufferUsages::VERTEX,
+        );
+        // Bindings can't be empty, a lone restart index draws nothing
+        let line_indices = if indices.is_empty() {
+            &[u32::MAX][..]
+        } else {
+            indices
+        };
+        let line_indices = buffer(bytemuck::cast_slice(line_indices), storage);
+        let fill_index_buffer = (!fill_indices.is_empty()).then(|| {
+            buffer(
+                bytemuck::cast_slice(fill_indices),
+                wgpu::BufferUsages::INDEX,
+            )
+        });
+        let bind_group = self.device.create_bind_group(&BindGroupDescriptor {
+            label: Some("Overlay"),
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: self.uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: styles.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: vertex_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: line_indices.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: self.no_chunk_buffer.as_entire_binding(),
+                },
+            ],
+        });
+        self.overlay = Some(Overlay {
+            bind_group,
+            segments: indices.len().saturating_sub(1) as u32,
+            vertex_buffer,
+            fill_index_buffer,
+            fill_indices: fill_indices.len() as u32,
+        });
     }
 
     pub fn render(&mut self) {
@@ -215,6 +469,8 @@ impl Graphics {
             self.surface.configure(&self.device, &self.config);
             return;
         };
+        let fills = self.visible_ranges(|batch| batch.fills.clone());
+        let lines = self.visible_ranges(|batch| batch.lines.clone());
         let view = output
             .texture
             .create_view(&TextureViewDescriptor::default());
@@ -237,24 +493,109 @@ impl Graphics {
                 })],
                 ..Default::default()
             });
+            // Fills read the vertex buffer as vertices rather than storage, so they're
+            // drawn in one go with any chunk's bindings for the uniforms and styles
+            if let (false, Some(chunk)) = (fills.is_empty(), self.chunks.first()) {
+                render_pass.set_pipeline(&self.fill_pipeline);
+                render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+                render_pass
+                    .set_index_buffer(self.fill_index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.set_bind_group(0, &chunk.bind_group, &[]);
+                for (_, range) in fills {
+                    render_pass.draw_indexed(range, 0, 0..1);
+                }
+            }
+
+            // One quad per pair of consecutive indices, the shader skips pairs across restarts
             render_pass.set_pipeline(&self.line_pipeline);
-            render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-            render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-            render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
-            render_pass.draw_indexed(0..self.num_indices, 0, 0..1);
-
-            // render_pass.set_pipeline(&self.point_pipeline);
-            // render_pass.set_vertex_buffer(0, self.point_buffer.slice(..));
-            // render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
-            // render_pass.draw(0..self.point_count, 0..1);
+            let mut bound = None;
+            for (chunk, range) in lines {
+                if bound != Some(chunk) {
+                    render_pass.set_bind_group(0, &self.chunks[chunk].bind_group, &[]);
+                    bound = Some(chunk);
+                }
+                let chunk = &self.chunks[chunk];
+                render_pass.draw(
+                    0..4,
+                    range.start - chunk.first_index..range.end - 1 - chunk.first_index,
+                );
+            }
+
+            if let Some(overlay) = &self.overlay {
+                render_pass.set_bind_group(0, &overlay.bind_group, &[]);
+                if let Some(fill_index_buffer) = &overlay.fill_index_buffer {
+                    render_pass.set_pipeline(&self.fill_pipeline);
+                    render_pass.set_vertex_buffer(0, overlay.vertex_buffer.slice(..));
+                    render_pass
+                        .set_index_buffer(fill_index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                    render_pass.draw_indexed(0..overlay.fill_indices, 0, 0..1);
+                    render_pass.set_pipeline(&self.line_pipeline);
+                }
+                render_pass.draw(0..4, 0..overlay.segments);
+            }
         }
         self.queue.submit(Some(encoder.finish()));
         smaa_frame.resolve();
         output.present();
     }
 
-    pub fn input(&self, event: &Event<()>) -> bool {
-        false
+    /// Ranges of the batches in view and the chunks they're in, with neighbouring
+    /// ranges in the same chunk merged into one draw
+    fn visible_ranges(&self, range: impl Fn(&Batch) -> Range<u32>) -> Vec<(usize, Range<u32>)> {
+        let mut ranges: Vec<(usize, Range<u32>)> = Vec::new();
+        let Some(lod) = self.lods.get(self.lod) else {
+            return ranges;
+        };
+        for batch in &lod.batches {
+            let visible =
+                batch.min.cmple(self.visible_max).all() && batch.max.cmpge(self.visible_min).all();
+            let hidden = self.hidden_layers & (1 << batch.layer.min(31)) != 0;
+            let batch_range = range(batch);
+            if !visible || hidden || batch_range.is_empty() {
+                continue;
+            }
+            let chunk = self
+                .chunks
+                .partition_point(|bound| bound.chunk.vertices.end <= batch.vertices.start);
+            match ranges.last_mut() {
+                // Line batches are split by a single restart index, which the merged draw skips over
+                Some((last_chunk, last))
+                    if *last_chunk == chunk && batch_range.start <= last.end + 1 =>
+                {
+                    last.end = batch_range.end
+                }
+                _ => ranges.push((chunk, batch_range)),
+            }
+        }
+        ranges
+    }
+
+    /// Keys 1 to 9 show and hide the layer with that number, when there's more than one
+    pub fn input(&mut self, event: &Event<()>) -> bool {
+        let Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            logical_key: Key::Character(key),
+                            state: ElementState::Pressed,
+                            repeat: false,
+                            ..
+                        },
+                    ..
+                },
+            ..
+        } = event
+        else {
+            return false;
+        };
+        match key.parse::<u32>() {
+            Ok(layer @ 1..=9) if self.layers > 1 && layer <= self.layers => {
+                self.hidden_layers ^= 1 << (layer - 1);
+                true
+            }
+            _ => false,
+        }
     }
 
     pub fn resize(&mut self, size: PhysicalSize<u32>) {
@@ -295,11 +636,10 @@ fn find_config(
     }
 }
 
-fn make_pipeline(
+fn make_fill_pipeline(
     device: &Device,
     config: &SurfaceConfiguration,
     layout: &PipelineLayout,
-    topology: PrimitiveTopology,
 ) -> RenderPipeline {
     let shader = device.create_shader_module(ShaderModuleDescriptor {
         label: None,
@@ -307,7 +647,7 @@ fn make_pipeline(
     });
     let vertex = wgpu::VertexState {
         module: &shader,
-        entry_point: "vs_main",
+        entry_point: "vs_fill",
         buffers: &[Vertex::desc()],
         compilation_options: wgpu::PipelineCompilationOptions::default(),
     };
@@ -316,7 +656,7 @@ fn make_pipeline(
         entry_point: "fs_main",
         targets: &[Some(wgpu::ColorTargetState {
             format: config.format,
-            blend: Some(wgpu::BlendState::REPLACE),
+            blend: Some(wgpu::BlendState::ALPHA_BLENDING),
             write_mask: wgpu::ColorWrites::ALL,
         })],
         compilation_options: wgpu::PipelineCompilationOptions::default(),
@@ -328,14 +668,11 @@ fn make_pipeline(
         vertex,
         fragment: Some(fragment),
         primitive: wgpu::PrimitiveState {
-            topology,
-            strip_index_format: if topology == wgpu::PrimitiveTopology::LineStrip {
-                Some(wgpu::IndexFormat::Uint32)
-            } else {
-                None
-            },
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
             front_face: wgpu::FrontFace::Ccw,
-            cull_mode: Some(wgpu::Face::Back),
+            // Triangulated areas come out with either winding
+            cull_mode: None,
             polygon_mode: wgpu::PolygonMode::Fill,
             conservative: false,
             unclipped_depth: false,
@@ -350,3 +687,155 @@ fn make_pipeline(
         cache: None,
     })
 }
+
+fn make_line_pipeline(
+    device: &Device,
+    config: &SurfaceConfiguration,
+    layout: &PipelineLayout,
+) -> RenderPipeline {
+    let shader = device.create_shader_module(ShaderModuleDescriptor {
+        label: None,
+        source: wgpu::ShaderSource::Wgsl(include_str!("../shaders/shader.wgsl").into()),
+    });
+    let vertex = wgpu::VertexState {
+        module: &shader,
+        entry_point: "vs_line",
+        buffers: &[],
+        compilation_options: wgpu::PipelineCompilationOptions::default(),
+    };
+    let fragment = FragmentState {
+        module: &shader,
+        entry_point: "fs_line",
+        targets: &[Some(wgpu::ColorTargetState {
+            format: config.format,
+            blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+            write_mask: wgpu::ColorWrites::ALL,
+        })],
+        compilation_options: wgpu::PipelineCompilationOptions::default(),
+    };
+
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("Line pipeline"),
+        layout: Some(layout),
+        vertex,
+        fragment: Some(fragment),
+        primitive: wgpu::PrimitiveState {
+            topology: PrimitiveTopology::TriangleStrip,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            // Winding depends on which way the segment points
+            cull_mode: None,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            conservative: false,
+            unclipped_depth: false,
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+        cache: None,
+    })
+}
+
+// Groups the line batches of every level of detail into chunks in vertex order, each
+// small enough that its vertices and line indices can be bound from the start of a
+// window aligned to `alignment` bytes without going over `limit` bytes
+fn chunks(lods: &[Lod], limit: u64, alignment: u64) -> Vec<Chunk> {
+    let mut batches: Vec<_> = lods
+        .iter()
+        .flat_map(|lod| &lod.batches)
+        .filter(|batch| !batch.lines.is_empty())
+        .collect();
+    batches.sort_by_key(|batch| batch.vertices.start);
+    let fits = |range: &Range<u32>, size: u64| {
+        range.end as u64 * size - range.start as u64 * size / alignment * alignment <= limit
+    };
+    let mut chunks: Vec<Chunk> = Vec::new();
+    for batch in batches {
+        if let Some(chunk) = chunks.last_mut() {
+            let merged = Chunk {
+                vertices: chunk.vertices.start..batch.vertices.end.max(chunk.vertices.end),
+                lines: chunk.lines.start.min(batch.lines.start)
+                    ..chunk.lines.end.max(batch.lines.end),
+            };
+            if fits(&merged.vertices, VERTEX_SIZE) && fits(&merged.lines, INDEX_SIZE) {
+                *chunk = merged;
+                continue;
+            }
+        }
+        chunks.push(Chunk {
+            vertices: batch.vertices.clone(),
+            lines: batch.lines.clone(),
+        });
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(vertices: Range<u32>, lines: Range<u32>) -> Batch {
+        Batch {
+            z_order: 0,
+            tile: (0, 0),
+            min: Vec2::ZERO,
+            max: Vec2::ZERO,
+            vertices,
+            lines,
+            fills: 0..0,
+            layer: 0,
+        }
+    }
+
+    #[test]
+    fn chunks_fit_the_binding_limit() {
+        let mut lods: Vec<_> = Lod::levels().take(2).collect();
+        lods[0].batches = vec![batch(0..10, 0..12), batch(10..20, 13..25)];
+        lods[1].batches = vec![batch(20..30, 26..38), batch(30..40, 0..0)];
+        // Everything fits at once
+        assert_eq!(
+            chunks(&lods, 1 << 20, 256),
+            [Chunk {
+                vertices: 0..30,
+                lines: 0..38
+            }]
+        );
+        // Room for 12 vertices, which the aligned window of the second batch still has
+        let limit = 12 * VERTEX_SIZE;
+        assert_eq!(
+            chunks(&lods, limit, 64),
+            [
+                Chunk {
+                    vertices: 0..10,
+                    lines: 0..12
+                },
+                Chunk {
+                    vertices: 10..20,
+                    lines: 13..25
+                },
+                Chunk {
+                    vertices: 20..30,
+                    lines: 26..38
+                },
+            ]
+        );
+        // Two batches fit, and the window of the third starts 16 bytes before it
+        assert_eq!(
+            chunks(&lods, 20 * VERTEX_SIZE, 64),
+            [
+                Chunk {
+                    vertices: 0..20,
+                    lines: 0..25
+                },
+                Chunk {
+                    vertices: 20..30,
+                    lines: 26..38
+                },
+            ]
+        );
+    }
+}
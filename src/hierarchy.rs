@@ -0,0 +1,425 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    graph::{OSMGraph, Queued, Route},
+    profile::Profile,
+};
+
+// Witness searches give up after settling this many nodes and the shortcut is added
+// anyway, which keeps preprocessing fast at the cost of some unneeded shortcuts
+const WITNESS_SETTLE_LIMIT: usize = 500;
+const NO_LINK: u32 = u32::MAX;
+
+/// Directed connection between two nodes of the hierarchy, either a turn from one
+/// road segment onto the next or a shortcut standing in for two links through a node
+/// contracted before both ends
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Link {
+    from: u32,
+    to: u32,
+    /// Travel time in seconds along the segments after `from`, up to and including `to`
+    time: f64,
+    /// Length in meters of the same segments
+    length: f64,
+    /// Links a shortcut replaces, `NO_LINK` for road segments
+    children: [u32; 2],
+}
+
+/// Contraction hierarchy over an `OSMGraph` for one routing profile. Nodes are
+/// removed one by one from least to most important, adding shortcuts so travel times
+/// between the rest stay the same. A query then only has to search upwards from
+/// both ends, which touches a tiny part of the graph.
+///
+/// Like `OSMGraph::plan_path_a_star` it works on road segments rather than junctions:
+/// each node of the hierarchy is an edge of the graph, linked to the edges that may
+/// be taken after it. Turn restrictions and U-turns are then just missing links, so
+/// routes follow them without checking afterwards.
+#[derive(Serialize, Deserialize)]
+pub struct ContractionHierarchy {
+    profile: Profile,
+    links: Vec<Link>,
+    /// Links leading to a higher ranked node, grouped by the node they leave
+    up_offsets: Vec<u32>,
+    up: Vec<u32>,
+    /// Links arriving from a higher ranked node, grouped by the node they arrive at
+    down_offsets: Vec<u32>,
+    down: Vec<u32>,
+}
+
+impl ContractionHierarchy {
+    pub fn build(graph: &OSMGraph, profile: Profile) -> Self {
+        let start = Instant::now();
+        let node_count = graph.start_state();
+        let mut links: Vec<Link> = Vec::new();
+        for from in 0..node_count {
+            let (_, edge) = graph.edge_by_index(from);
+            if profile.time(edge).is_none() {
+                continue;
+            }
+            let (_, moves) = graph.moves(from, 0, profile);
+            for (to, edge, time) in moves.filter(|&(to, _, _)| to != from) {
+                links.push(Link {
+                    from: from as u32,
+                    to: to as u32,
+                    time,
+                    length: edge.length,
+                    children: [NO_LINK; 2],
+                });
+            }
+        }
+        let segments = links.len();
+
+        let mut contraction = Contraction::new(node_count, &links);
+        let mut queue: BinaryHeap<_> = (0..node_count as u32)
+            .map(|node| Reverse((contraction.priority(node, &links), node)))
+            .collect();
+        let mut rank = vec![0; node_count];
+        let mut next_rank = 0;
+        while let Some(Reverse((priority, node))) = queue.pop() {
+            // Priorities go stale as neighbours are contracted, so they're checked lazily
+            let shortcuts = contraction.shortcuts(node, &links);
+            let current = contraction.priority_with(node, shortcuts.len(), &links);
+            if current > priority
+                && queue
+                    .peek()
+                    .is_some_and(|Reverse((next, _))| current > *next)
+            {
+                queue.push(Reverse((current, node)));
+                continue;
+            }
+            for shortcut in shortcuts {
+                contraction.add(links.len() as u32, &shortcut);
+                links.push(shortcut);
+            }
+            contraction.contract(node, &links);
+            rank[node as usize] = next_rank;
+            next_rank += 1;
+        }
+
+        let (up_offsets, up) = group(node_count, &links, |link| {
+            (rank[link.from as usize] < rank[link.to as usize]).then_some(link.from)
+        });
+        let (down_offsets, down) = group(node_count, &links, |link| {
+            (rank[link.from as usize] > rank[link.to as usize]).then_some(link.to)
+        });
+        eprintln!(
+            "Contraction hierarchy for {}: {} shortcuts over {} turns, took {:.2?}",
+            profile,
+            links.len() - segments,
+            segments,
+            start.elapsed()
+        );
+        Self {
+            profile,
+            links,
+            up_offsets,
+            up,
+            down_offsets,
+            down,
+        }
+    }
+
+    /// Fastest path from `a` to `b`, the same as `OSMGraph::plan_path_a_star` with
+    /// the hierarchy's profile
+    pub fn route(&self, graph: &OSMGraph, a: usize, b: usize) -> Option<Route> {
+        if a == b {
+            return Some(Route {
+                nodes: vec![a],
+                distance: 0.0,
+                time: 0.0,
+            });
+        }
+        // Time to each reached edge and the link it was reached by. Forwards that's
+        // the time to its end from `a`, backwards from its end to `b`.
+        let (_, first) = graph.moves(graph.start_state(), a, self.profile);
+        let mut forward = HashMap::new();
+        for (state, _, time) in first {
+            forward.insert(state, (time, NO_LINK));
+        }
+        let mut backward: HashMap<_, _> = graph
+            .arrivals(b)
+            .into_iter()
+            .map(|state| (state, (0.0, NO_LINK)))
+            .collect();
+        let queued = |reached: &HashMap<usize, (f64, u32)>| -> BinaryHeap<_> {
+            reached
+                .iter()
+                .map(|(&node, &(estimate, _))| Queued { estimate, node })
+                .collect()
+        };
+        let mut forward_queue = queued(&forward);
+        let mut backward_queue = queued(&backward);
+        let mut best = f64::INFINITY;
+        let mut meeting = None;
+        loop {
+            let forward_min = forward_queue.peek().map_or(f64::INFINITY, |q| q.estimate);
+            let backward_min = backward_queue.peek().map_or(f64::INFINITY, |q| q.estimate);
+            // Neither side can improve on the best meeting point anymore
+            if forward_min.min(backward_min) >= best {
+                break;
+            }
+            let is_forward = forward_min <= backward_min;
+            let (queue, reached, other, offsets, links) = if is_forward {
+                (
+                    &mut forward_queue,
+                    &mut forward,
+                    &backward,
+                    &self.up_offsets,
+                    &self.up,
+                )
+            } else {
+                (
+                    &mut backward_queue,
+                    &mut backward,
+                    &forward,
+                    &self.down_offsets,
+                    &self.down,
+                )
+            };
+            let Queued { estimate, node } = queue.pop().unwrap();
+            if estimate > reached[&node].0 {
+                continue;
+            }
+            if let Some(&(time, _)) = other.get(&node) {
+                if estimate + time < best {
+                    best = estimate + time;
+                    meeting = Some(node);
+                }
+            }
+            for &index in &links[offsets[node] as usize..offsets[node + 1] as usize] {
+                let link = &self.links[index as usize];
+                let next = if is_forward { link.to } else { link.from } as usize;
+                let time = estimate + link.time;
+                if reached.get(&next).is_none_or(|&(known, _)| time < known) {
+                    reached.insert(next, (time, index));
+                    queue.push(Queued {
+                        estimate: time,
+                        node: next,
+                    });
+                }
+            }
+        }
+
+        let meeting = meeting?;
+        let mut path_links = Vec::new();
+        let mut node = meeting;
+        while let Some(&(_, index)) = forward.get(&node).filter(|(_, index)| *index != NO_LINK) {
+            path_links.push(index);
+            node = self.links[index as usize].from as usize;
+        }
+        path_links.reverse();
+        let first = node;
+        let mut node = meeting;
+        while let Some(&(_, index)) = backward.get(&node).filter(|(_, index)| *index != NO_LINK) {
+            path_links.push(index);
+            node = self.links[index as usize].to as usize;
+        }
+
+        let (_, first) = graph.edge_by_index(first);
+        let mut nodes = vec![a, first.to];
+        let mut distance = first.length;
+        for index in path_links {
+            self.unpack(graph, index, &mut nodes, &mut distance);
+        }
+        Some(Route {
+            nodes,
+            distance,
+            time: best,
+        })
+    }
+
+    // Appends the end of every road segment a link stands for
+    fn unpack(&self, graph: &OSMGraph, index: u32, nodes: &mut Vec<usize>, distance: &mut f64) {
+        let link = &self.links[index as usize];
+        if link.children[0] == NO_LINK {
+            nodes.push(graph.edge_by_index(link.to as usize).1.to);
+            *distance += link.length;
+        } else {
+            self.unpack(graph, link.children[0], nodes, distance);
+            self.unpack(graph, link.children[1], nodes, distance);
+        }
+    }
+}
+
+/// Graph of the nodes that haven't been contracted yet, used while building
+struct Contraction {
+    outgoing: Vec<Vec<u32>>,
+    incoming: Vec<Vec<u32>>,
+    contracted: Vec<bool>,
+    /// Neighbours contracted so far, spreads contraction evenly over the graph
+    contracted_neighbours: Vec<i64>,
+    // Reused by every witness search, only the entries in `touched` are reset
+    times: Vec<f64>,
+    touched: Vec<u32>,
+}
+
+impl Contraction {
+    fn new(node_count: usize, links: &[Link]) -> Self {
+        let mut contraction = Self {
+            outgoing: vec![Vec::new(); node_count],
+            incoming: vec![Vec::new(); node_count],
+            contracted: vec![false; node_count],
+            contracted_neighbours: vec![0; node_count],
+            times: vec![f64::INFINITY; node_count],
+            touched: Vec::new(),
+        };
+        for (index, link) in links.iter().enumerate() {
+            contraction.add(index as u32, link);
+        }
+        contraction
+    }
+
+    fn add(&mut self, index: u32, link: &Link) {
+        self.outgoing[link.from as usize].push(index);
+        self.incoming[link.to as usize].push(index);
+    }
+
+    fn priority(&mut self, node: u32, links: &[Link]) -> i64 {
+        let shortcuts = self.shortcuts(node, links).len();
+        self.priority_with(node, shortcuts, links)
+    }
+
+    // Edge difference plus contracted neighbours
+    fn priority_with(&self, node: u32, shortcuts: usize, links: &[Link]) -> i64 {
+        let live = |indices: &[u32], end: fn(&Link) -> u32| {
+            indices
+                .iter()
+                .filter(|&&index| !self.contracted[end(&links[index as usize]) as usize])
+                .count()
+        };
+        let removed = live(&self.outgoing[node as usize], |link| link.to)
+            + live(&self.incoming[node as usize], |link| link.from);
+        shortcuts as i64 - removed as i64 + self.contracted_neighbours[node as usize]
+    }
+
+    /// Shortcuts needed to keep travel times between the neighbours of `node` when
+    /// it's removed
+    fn shortcuts(&mut self, node: u32, links: &[Link]) -> Vec<Link> {
+        let outgoing: Vec<u32> = self.outgoing[node as usize]
+            .iter()
+            .copied()
+            .filter(|&index| !self.contracted[links[index as usize].to as usize])
+            .collect();
+        let mut shortcuts = Vec::new();
+        for &in_index in &self.incoming[node as usize].clone() {
+            let incoming = &links[in_index as usize];
+            let from = incoming.from;
+            if self.contracted[from as usize] {
+                continue;
+            }
+            let Some(limit) = outgoing
+                .iter()
+                .map(|&index| &links[index as usize])
+                .filter(|link| link.to != from)
+                .map(|link| incoming.time + link.time)
+                .max_by(f64::total_cmp)
+            else {
+                continue;
+            };
+            self.witness_search(from, node, limit, links);
+            for &out_index in &outgoing {
+                let outgoing = &links[out_index as usize];
+                let time = incoming.time + outgoing.time;
+                if outgoing.to != from && self.times[outgoing.to as usize] > time {
+                    shortcuts.push(Link {
+                        from,
+                        to: outgoing.to,
+                        time,
+                        length: incoming.length + outgoing.length,
+                        children: [in_index, out_index],
+                    });
+                }
+            }
+        }
+        shortcuts
+    }
+
+    // Dijkstra from `from` that avoids `skip`, leaving travel times in `times`
+    fn witness_search(&mut self, from: u32, skip: u32, limit: f64, links: &[Link]) {
+        for node in self.touched.drain(..) {
+            self.times[node as usize] = f64::INFINITY;
+        }
+        self.times[from as usize] = 0.0;
+        self.touched.push(from);
+        let mut queue = BinaryHeap::from([Queued {
+            estimate: 0.0,
+            node: from as usize,
+        }]);
+        let mut settled = 0;
+        while let Some(Queued { estimate, node }) = queue.pop() {
+            if estimate > self.times[node] {
+                continue;
+            }
+            settled += 1;
+            if estimate > limit || settled > WITNESS_SETTLE_LIMIT {
+                break;
+            }
+            for &index in &self.outgoing[node] {
+                let link = &links[index as usize];
+                let next = link.to as usize;
+                if next == skip as usize || self.contracted[next] {
+                    continue;
+                }
+                let time = estimate + link.time;
+                if time < self.times[next] {
+                    if self.times[next] == f64::INFINITY {
+                        self.touched.push(link.to);
+                    }
+                    self.times[next] = time;
+                    queue.push(Queued {
+                        estimate: time,
+                        node: next,
+                    });
+                }
+            }
+        }
+    }
+
+    fn contract(&mut self, node: u32, links: &[Link]) {
+        self.contracted[node as usize] = true;
+        let neighbours = self.outgoing[node as usize]
+            .iter()
+            .map(|&index| links[index as usize].to)
+            .chain(
+                self.incoming[node as usize]
+                    .iter()
+                    .map(|&index| links[index as usize].from),
+            );
+        for neighbour in neighbours.collect::<Vec<_>>() {
+            self.contracted_neighbours[neighbour as usize] += 1;
+        }
+    }
+}
+
+/// Link indices grouped by the node `key` gives, leaving out links it gives `None`
+/// for. Links of node `i` are at `offsets[i]..offsets[i + 1]`.
+fn group(
+    node_count: usize,
+    links: &[Link],
+    key: impl Fn(&Link) -> Option<u32>,
+) -> (Vec<u32>, Vec<u32>) {
+    let mut offsets = vec![0; node_count + 1];
+    for link in links {
+        if let Some(node) = key(link) {
+            offsets[node as usize + 1] += 1;
+        }
+    }
+    for i in 0..node_count {
+        offsets[i + 1] += offsets[i];
+    }
+    let mut grouped = vec![0; offsets[node_count] as usize];
+    let mut next = offsets.clone();
+    for (index, link) in links.iter().enumerate() {
+        if let Some(node) = key(link) {
+            grouped[next[node as usize] as usize] = index as u32;
+            next[node as usize] += 1;
+        }
+    }
+    (offsets, grouped)
+}
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+
+use glam::DVec2;
+
+use crate::{
+    graph::OSMGraph,
+    profile::Profile,
+    projection::{distance_meters, Projection},
+    style::GpuStyle,
+    vertex::Vertex,
+};
+
+/// Road colors from the start to the largest threshold, each covering an equal share
+/// of the time
+const TIME_COLORS: [&str; 8] = [
+    "#2ecc40", "#6fd035", "#a8d42b", "#dcd320", "#ffc21a", "#ff9a1f", "#ff6d28", "#ff4136",
+];
+/// Fills of the bands from the innermost outwards
+const BAND_COLORS: [&str; 4] = ["#2ecc4070", "#ffdc0060", "#ff851b50", "#ff413640"];
+const ROAD_WIDTH: f32 = 3.0;
+const START_COLOR: &str = "#2ecc40";
+const MARKER_SIZE: f32 = 16.0;
+/// Reached nodes are thinned out to one per cell of a grid this many cells across the
+/// isochrone before the hull is built, which keeps the triangulation small
+const HULL_GRID: f64 = 48.0;
+/// Triangles with a side longer than this many cells are left out of the hull, which
+/// is what lets it follow the roads into concave shapes
+const HULL_MAX_EDGE: f64 = 3.0;
+/// Share of the size of its terms below which the circumcircle determinant counts as
+/// zero, so rounding can't make cocircular points disagree about their triangles
+const CIRCLE_EPSILON: f64 = 1e-12;
+
+/// Everything reachable from one node within a few travel times, shown as the roads
+/// colored by time and a filled band for each threshold
+pub struct Isochrone {
+    start: usize,
+    profile: Profile,
+    /// In seconds, ascending
+    thresholds: Vec<f64>,
+    /// Fastest time to each node reached within the largest threshold
+    times: HashMap<usize, f64>,
+}
+
+impl Isochrone {
+    pub fn new(graph: &OSMGraph, start: usize, profile: Profile, minutes: &[f64]) -> Self {
+        let mut thresholds: Vec<_> = minutes.iter().map(|minutes| minutes * 60.0).collect();
+        thresholds.sort_by(f64::total_cmp);
+        let limit = thresholds.last().copied().unwrap_or_default();
+        Self {
+            start,
+            profile,
+            times: graph.travel_times(start, profile, limit),
+            thresholds,
+        }
+    }
+
+    /// The thresholds and how far the isochrone reaches
+    pub fn summary(&self, graph: &OSMGraph) -> String {
+        let minutes: Vec<_> = self
+            .thresholds
+            .iter()
+            .map(|seconds| format!("{}", seconds / 60.0))
+            .collect();
+        let start = graph.nodes()[self.start].pos;
+        let reach = self
+            .times
+            .keys()
+            .map(|&node| distance_meters(start, graph.nodes()[node].pos))
+            .fold(0.0, f64::max);
+        format!(
+            "{} min by {}, up to {:.1} km away",
+            minutes.join("/"),
+            self.profile,
+            reach / 1000.0
+        )
+    }
+
+    /// Vertices, line indices, fill indices and styles for `Graphics::set_overlay`: the
+    /// bands, each hull triangle in the one of the smallest threshold it's within, the
+    /// roads colored by travel time over them and a dot at the start
+    pub fn overlay(
+        &self,
+        graph: &OSMGraph,
+        projection: Projection,
+        origin: DVec2,
+    ) -> (Vec<Vertex>, Vec<u32>, Vec<u32>, Vec<GpuStyle>) {
+        let mut styles = vec![GpuStyle::overlay(START_COLOR, MARKER_SIZE)];
+        let road_styles = styles.len() as u32;
+        styles.extend(
+            TIME_COLORS
+                .iter()
+                .map(|color| GpuStyle::overlay(color, ROAD_WIDTH)),
+        );
+        let band_styles = styles.len() as u32;
+        styles.extend(
+            (0..self.thresholds.len())
+                .map(|band| GpuStyle::overlay_area(BAND_COLORS[band % BAND_COLORS.len()])),
+        );
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        let mut fill_indices = Vec::new();
+        let Some(&limit) = self.thresholds.last() else {
+            return (vertices, indices, fill_indices, styles);
+        };
+        let project = |pos: DVec2| projection.project(pos) - origin;
+
+        let (points, point_times, cell) = self.hull_points(graph, project);
+        let max_edge = cell * HULL_MAX_EDGE;
+        for triangle in triangulate(&points) {
+            let short = (0..3).all(|side| {
+                points[triangle[side]].distance(points[triangle[(side + 1) % 3]]) <= max_edge
+            });
+            let time = triangle.iter().map(|&i| point_times[i]).fold(0.0, f64::max);
+            let band = self
+                .thresholds
+                .iter()
+                .position(|&threshold| time <= threshold);
+            let (true, Some(band)) = (short, band) else {
+                continue;
+            };
+            for i in triangle {
+                fill_indices.push(vertices.len() as u32);
+                vertices.push(Vertex::new(points[i], band_styles + band as u32));
+            }
+        }
+
+        let mut segment = |a: DVec2, b: DVec2, style: u32| {
+            if !indices.is_empty() {
+                indices.push(u32::MAX);
+            }
+            for pos in [a, b] {
+                indices.push(vertices.len() as u32);
+                vertices.push(Vertex::new(pos, style));
+            }
+        };
+        for (&node, &time) in &self.times {
+            for edge in graph.edges(node) {
+                let Some(edge_time) = self.profile.time(edge) else {
+                    continue;
+                };
+                // Roads reached from both ends are drawn from the one reached first
+                let drawn_from_other_end = self.times.get(&edge.to).is_some_and(|&other| {
+                    (other, edge.to) < (time, node)
+                        && graph
+                            .edges(edge.to)
+                            .iter()
+                            .any(|back| back.to == node && self.profile.time(back).is_some())
+                });
+                let reach = (limit - time).min(edge_time);
+                if drawn_from_other_end || reach <= 0.0 {
+                    continue;
+                }
+                let from = graph.nodes()[node].pos;
+                let to = from.lerp(graph.nodes()[edge.to].pos, reach / edge_time);
+                let color = ((time + reach / 2.0) / limit * TIME_COLORS.len() as f64) as usize;
+                segment(
+                    project(from),
+                    project(to),
+                    road_styles + color.min(TIME_COLORS.len() - 1) as u32,
+                );
+            }
+        }
+        let start = project(graph.nodes()[self.start].pos);
+        segment(start, start, 0);
+        (vertices, indices, fill_indices, styles)
+    }
+
+    // Projected positions of the reached nodes thinned out to one per grid cell, the
+    // fastest in each, with their times and the cell size
+    fn hull_points(
+        &self,
+        graph: &OSMGraph,
+        project: impl Fn(DVec2) -> DVec2,
+    ) -> (Vec<DVec2>, Vec<f64>, f64) {
+        let reached: Vec<_> = self
+            .times
+            .iter()
+            .map(|(&node, &time)| (project(graph.nodes()[node].pos), time))
+            .collect();
+        let (min, max) = reached
+            .iter()
+            .fold((DVec2::MAX, DVec2::MIN), |(min, max), &(pos, _)| {
+                (min.min(pos), max.max(pos))
+            });
+        let cell = ((max - min).max_element() / HULL_GRID).max(f64::EPSILON);
+        let mut fastest: HashMap<(i64, i64), (DVec2, f64)> = HashMap::new();
+        for (pos, time) in reached {
+            let key = ((pos - min) / cell).floor();
+            let key = (key.x as i64, key.y as i64);
+            if fastest.get(&key).is_none_or(|&(_, known)| time < known) {
+                fastest.insert(key, (pos, time));
+            }
+        }
+        let (points, times) = fastest.into_values().unzip();
+        (points, times, cell)
+    }
+}
+
+/// Delaunay triangulation of `points` as counter-clockwise index triples, adding one
+/// point at a time to a triangle around all of them (Bowyer-Watson)
+fn triangulate(points: &[DVec2]) -> Vec<[usize; 3]> {
+    if points.len() < 3 {
+        return Vec::new();
+    }
+    let (min, max) = points
+        .iter()
+        .fold((DVec2::MAX, DVec2::MIN), |(min, max), &point| {
+            (min.min(point), max.max(point))
+        });
+    let center = (min + max) / 2.0;
+    let size = (max - min).max_element().max(1.0) * 20.0;
+    let n = points.len();
+    let mut all = points.to_vec();
+    all.extend([
+        center + DVec2::new(-size, -size),
+        center + DVec2::new(size, -size),
+        center + DVec2::new(0.0, size),
+    ]);
+
+    let mut triangles = vec![[n, n + 1, n + 2]];
+    let mut edges = Vec::new();
+    for (i, &point) in points.iter().enumerate() {
+        edges.clear();
+        triangles.retain(|triangle| {
+            let outside = !in_circumcircle(&all, triangle, point);
+            if !outside {
+                edges.extend((0..3).map(|side| (triangle[side], triangle[(side + 1) % 3])));
+            }
+            outside
+        });
+        // Sides shared by two removed triangles are inside the hole, the rest outline it
+        for &(a, b) in &edges {
+            if !edges.contains(&(b, a)) {
+                triangles.push([a, b, i]);
+            }
+        }
+    }
+    triangles.retain(|triangle| triangle.iter().all(|&i| i < n));
+    triangles
+}
+
+// Whether `point` is inside the circle through the corners of a counter-clockwise
+// triangle
+fn in_circumcircle(points: &[DVec2], triangle: &[usize; 3], point: DVec2) -> bool {
+    let [a, b, c] = triangle.map(|i| points[i] - point);
+    let terms = [
+        a.length_squared() * b.perp_dot(c),
+        -b.length_squared() * a.perp_dot(c),
+        c.length_squared() * a.perp_dot(b),
+    ];
+    // Points on the circle count as outside, which keeps the triangles already there
+    let det: f64 = terms.iter().sum();
+    det > terms.iter().map(|term| term.abs()).sum::<f64>() * CIRCLE_EPSILON
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triangulates_square_grids() {
+        // The corners of each square are cocircular, exactly so on the first grid and up
+        // to rounding on the others
+        for (origin, spacing) in [(0.0, 1.0), (1234.5, 0.1), (-73.3, 0.3)] {
+            let points: Vec<_> = (0..25)
+                .map(|i| {
+                    DVec2::splat(origin) + DVec2::new((i % 5) as f64, (i / 5) as f64) * spacing
+                })
+                .collect();
+            let triangles = triangulate(&points);
+            assert_eq!(triangles.len(), 2 * 4 * 4);
+            // Counter-clockwise and covering the grid without overlaps
+            let area: f64 = triangles
+                .iter()
+                .map(|&[a, b, c]| (points[b] - points[a]).perp_dot(points[c] - points[a]) / 2.0)
+                .inspect(|&area| assert!(area > 0.0))
+                .sum();
+            assert!((area - (4.0 * spacing).powi(2)).abs() < 1e-9);
+        }
+    }
+}
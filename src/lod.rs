@@ -0,0 +1,144 @@
+use glam::DVec2;
+use serde::{Deserialize, Serialize};
+
+use crate::Batch;
+
+/// Zoom level each level of detail starts at, from most to least detailed. A level
+/// is used until the zoom reaches the start of the one before it.
+pub const LOD_MIN_ZOOMS: [f32; 5] = [14.0, 11.0, 8.0, 5.0, f32::MIN];
+
+/// Ways simplified for a range of zoom levels, with ways that wouldn't be visible
+/// in that range left out entirely
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Lod {
+    pub min_zoom: f32,
+    pub max_zoom: f32,
+    /// Largest distance in world units a simplified way strays from the original
+    pub tolerance: f64,
+    pub batches: Vec<Batch>,
+}
+
+impl Lod {
+    pub fn levels() -> impl Iterator<Item = Lod> {
+        LOD_MIN_ZOOMS.iter().enumerate().map(|(i, &min_zoom)| {
+            let max_zoom = if i == 0 {
+                f32::MAX
+            } else {
+                LOD_MIN_ZOOMS[i - 1]
+            };
+            Lod {
+                min_zoom,
+                max_zoom,
+                // Half a pixel at the most zoomed in end of the range
+                tolerance: if i == 0 {
+                    0.0
+                } else {
+                    pixel_size(max_zoom) / 2.0
+                },
+                batches: Vec::new(),
+            }
+        })
+    }
+
+    pub fn contains(&self, zoom_level: f32) -> bool {
+        zoom_level >= self.min_zoom && zoom_level < self.max_zoom
+    }
+}
+
+/// World units covered by one pixel at a slippy map zoom level
+pub fn pixel_size(zoom_level: f32) -> f64 {
+    360.0 / (256.0 * 2f64.powf(zoom_level as f64))
+}
+
+/// Douglas-Peucker, keeping the first and last node
+pub fn simplify(refs: &[usize], positions: &[DVec2], tolerance: f64) -> Vec<usize> {
+    if tolerance <= 0.0 || refs.len() <= 2 {
+        return refs.to_vec();
+    }
+    let mut keep = vec![false; refs.len()];
+    keep[0] = true;
+    keep[refs.len() - 1] = true;
+    let mut stack = vec![(0, refs.len() - 1)];
+    while let Some((start, end)) = stack.pop() {
+        let a = positions[refs[start]];
+        let b = positions[refs[end]];
+        let mut furthest = None;
+        let mut max_distance = tolerance;
+        for i in start + 1..end {
+            let distance = segment_distance(positions[refs[i]], a, b);
+            if distance > max_distance {
+                max_distance = distance;
+                furthest = Some(i);
+            }
+        }
+        if let Some(i) = furthest {
+            keep[i] = true;
+            stack.push((start, i));
+            stack.push((i, end));
+        }
+    }
+    refs.iter()
+        .zip(keep)
+        .filter_map(|(&node, keep)| keep.then_some(node))
+        .collect()
+}
+
+fn segment_distance(point: DVec2, a: DVec2, b: DVec2) -> f64 {
+    let ab = b - a;
+    let length_squared = ab.length_squared();
+    if length_squared == 0.0 {
+        return point.distance(a);
+    }
+    let t = ((point - a).dot(ab) / length_squared).clamp(0.0, 1.0);
+    point.distance(a + ab * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Distance of the dropped nodes to the simplified line
+    fn max_error(refs: &[usize], simplified: &[usize], positions: &[DVec2]) -> f64 {
+        refs.iter()
+            .map(|&node| {
+                simplified
+                    .windows(2)
+                    .map(|pair| {
+                        segment_distance(positions[node], positions[pair[0]], positions[pair[1]])
+                    })
+                    .fold(f64::INFINITY, f64::min)
+            })
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn simplify_keeps_the_ends_and_the_shape() {
+        // A wobbly line with one spike, the refs point into the positions backwards
+        let positions: Vec<_> = (0..20)
+            .rev()
+            .map(|x| {
+                let y = if x == 12 { 5.0 } else { (x % 2) as f64 * 0.1 };
+                DVec2::new(x as f64, y)
+            })
+            .collect();
+        let refs: Vec<_> = (0..20).rev().collect();
+        let simplified = simplify(&refs, &positions, 0.5);
+        assert_eq!(simplified, [19, 8, 7, 6, 0]);
+        assert!(max_error(&refs, &simplified, &positions) <= 0.5);
+
+        // Nothing is dropped below the tolerance or without nodes between the ends
+        assert_eq!(simplify(&refs, &positions, 0.01), refs);
+        assert_eq!(simplify(&refs, &positions, 0.0), refs);
+        assert_eq!(simplify(&[3, 5], &positions, 100.0), [3, 5]);
+
+        // A ring stays closed and keeps its far side
+        let square = [
+            DVec2::new(0.0, 0.0),
+            DVec2::new(1.0, 0.0),
+            DVec2::new(1.0, 1.0),
+            DVec2::new(0.0, 1.0),
+        ];
+        assert_eq!(simplify(&[0, 1, 2, 3, 0], &square, 0.1), [0, 1, 2, 3, 0]);
+        assert_eq!(simplify(&[0, 1, 2, 3, 0], &square, 2.0), [0, 0]);
+    }
+}
@@ -1,56 +1,467 @@
 use std::{
-    collections::HashMap,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    ops::Range,
     os::raw,
     path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    thread::JoinHandle,
 };
 
-use anyhow::Result;
-use compression::prelude::*;
+use anyhow::{Context, Result};
+use cache::{Array, DatasetKey, FileStamp, HierarchyKey};
+use clip::Clip;
+use filter::Filter;
 use glam::{Vec2, Vec3, Vec4};
+use graph::OSMGraph;
 use graphics::Graphics;
+use hierarchy::ContractionHierarchy;
+use osm::{LoadOptions, OSM};
+use planner::RoutePlanner;
 use pollster::FutureExt;
+use profile::Profile;
 use radix_trie::Trie;
 use vertex::Vertex;
 use winit::{
-    event::{Event, WindowEvent},
+    event::{ElementState, Event, KeyEvent, MouseButton, WindowEvent},
     event_loop::EventLoop,
+    keyboard::{Key, NamedKey},
     window::WindowBuilder,
 };
+mod cache;
 mod camera;
+mod clip;
+mod filter;
+mod graph;
 mod graphics;
+mod hierarchy;
+mod isochrone;
+mod lod;
+mod multipolygon;
 mod osm;
+mod planner;
+mod profile;
+mod projection;
 mod shaders;
+mod style;
 mod vertex;
+mod xml;
 
+use glam::DVec2;
+use lod::Lod;
+use projection::Projection;
 use serde::{Deserialize, Serialize};
+use style::{GpuStyle, StyleSheet};
+
+/// Side length of the square tiles ways are bucketed into, in world units
+const TILE_SIZE: f64 = 0.05;
+/// Pixels the cursor may move between press and release for it to count as a click
+/// instead of a drag
+const CLICK_DISTANCE: f32 = 4.0;
+const TITLE: &str = "WGPU OSM View";
+
+/// A run of ways or areas with the same z-order in one tile. Batches are stored
+/// in draw order, so ranges of neighbouring batches are contiguous in the buffers.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Batch {
+    pub z_order: i32,
+    pub tile: (i32, i32),
+    /// Bounds of everything in the batch, which can reach past the tile
+    pub min: Vec2,
+    pub max: Vec2,
+    /// Only used by this batch
+    pub vertices: Range<u32>,
+    pub lines: Range<u32>,
+    pub fills: Range<u32>,
+    /// Input file the batch came from when files are drawn as separate layers
+    pub layer: u32,
+}
+
+#[derive(Default)]
+struct BatchBuilder {
+    min: DVec2,
+    max: DVec2,
+    ways: Vec<(Vec<usize>, u32)>,
+    areas: Vec<(Vec<usize>, Vec<Vec<usize>>, u32)>,
+}
+
+impl BatchBuilder {
+    fn extend(&mut self, (min, max): (DVec2, DVec2)) {
+        if self.ways.is_empty() && self.areas.is_empty() {
+            self.min = min;
+            self.max = max;
+        } else {
+            self.min = self.min.min(min);
+            self.max = self.max.max(max);
+        }
+    }
+}
+
+fn bounds(ring: &[usize], positions: &[DVec2]) -> (DVec2, DVec2) {
+    ring.iter().fold(
+        (DVec2::splat(f64::MAX), DVec2::splat(f64::MIN)),
+        |(min, max), &node| (min.min(positions[node]), max.max(positions[node])),
+    )
+}
+
+// Tiles are counted from the projection's origin rather than the data's, so they stay
+// put when changes move the center of the data
+fn tile_key((min, max): (DVec2, DVec2), origin: DVec2) -> (i32, i32) {
+    let tile = ((origin + (min + max) / 2.0) / TILE_SIZE).floor();
+    (tile.x as i32, tile.y as i32)
+}
+
+// Feeds what a way or area looks like into the hash of its tile
+fn hash_rings<'a>(
+    hasher: &mut DefaultHasher,
+    rings: impl IntoIterator<Item = &'a [usize]>,
+    style: u32,
+    osm: &OSM,
+) {
+    style.hash(hasher);
+    for ring in rings {
+        ring.len().hash(hasher);
+        for &node in ring {
+            let pos = osm.nodes()[node].pos;
+            (pos.x.to_bits(), pos.y.to_bits()).hash(hasher);
+        }
+    }
+}
 
-#[derive(Deserialize, Serialize)]
 pub struct RawRenderData {
-    pub vertices: Vec<Vertex>,
-    pub indices: Vec<u32>,
+    pub vertices: Array<Vertex>,
+    /// Line strips separated by `u32::MAX`
+    pub indices: Array<u32>,
+    /// Triangle list for filled areas, drawn before the lines
+    pub fill_indices: Array<u32>,
+    /// Every level has its own copy of the ways in the buffers above
+    pub lods: Vec<Lod>,
+    pub styles: Vec<GpuStyle>,
+    pub projection: Projection,
+    /// Projected position the vertices are relative to
+    pub origin: DVec2,
+    /// Hash of the ways and areas in each tile, which tells `updated` what changed
+    pub tile_hashes: HashMap<(i32, i32), u64>,
 }
 
 impl RawRenderData {
-    pub fn new(vertices: Vec<Vertex>, indices: Vec<u32>) -> Self {
-        Self { vertices, indices }
+    pub fn from_osm(osm: &osm::OSM, style: &StyleSheet, projection: Projection) -> Self {
+        Self::build(osm, style, projection, None)
+    }
+
+    /// Like `from_osm`, but tiles with the same ways and areas as in `previous` are
+    /// copied from it instead of being simplified and triangulated again. `previous`
+    /// has to be drawn with the same style and projection.
+    pub fn updated(
+        previous: &RawRenderData,
+        osm: &osm::OSM,
+        style: &StyleSheet,
+        projection: Projection,
+    ) -> Self {
+        Self::build(osm, style, projection, Some(previous))
+    }
+
+    fn build(
+        osm: &osm::OSM,
+        style: &StyleSheet,
+        projection: Projection,
+        previous: Option<&RawRenderData>,
+    ) -> Self {
+        let positions = osm.positions(projection);
+        let origin = osm.origin(projection);
+        let styles = style.styles();
+        let z_order = |style: u32| styles[style as usize].z_order as i32;
+
+        let lines: Vec<_> = osm
+            .ways()
+            .iter()
+            .filter(|way| way.refs.len() >= 2)
+            .map(|way| (way.refs.as_slice(), style.style_for(&way.tags)))
+            .collect();
+
+        let mut areas = Vec::new();
+        for way in osm.ways().iter().filter(|way| way.is_closed()) {
+            let way_style = style.style_for(&way.tags);
+            if style.has_fill(way_style) {
+                areas.push((way.refs.as_slice(), Vec::new(), way_style));
+            }
+        }
+        for multipolygon in osm.multipolygons() {
+            let relation = &osm.relations()[multipolygon.relation];
+            let area_style = style.style_for(&relation.tags);
+            if style.has_fill(area_style) {
+                for (outer, holes) in multipolygon.polygons(&positions) {
+                    areas.push((outer, holes, area_style));
+                }
+            }
+        }
+
+        let mut hashers: HashMap<_, DefaultHasher> = HashMap::new();
+        for &(refs, way_style) in &lines {
+            let tile = tile_key(bounds(refs, &positions), origin);
+            hash_rings(hashers.entry(tile).or_default(), [refs], way_style, osm);
+        }
+        for (outer, holes, area_style) in &areas {
+            let tile = tile_key(bounds(outer, &positions), origin);
+            let rings = std::iter::once(*outer).chain(holes.iter().copied());
+            hash_rings(hashers.entry(tile).or_default(), rings, *area_style, osm);
+        }
+        let tile_hashes: HashMap<_, _> = hashers
+            .into_iter()
+            .map(|(tile, hasher)| (tile, hasher.finish()))
+            .collect();
+        let reused: HashSet<_> = previous
+            .iter()
+            .flat_map(|previous| &previous.tile_hashes)
+            .filter(|&(tile, hash)| tile_hashes.get(tile) == Some(hash))
+            .map(|(&tile, _)| tile)
+            .collect();
+        if previous.is_some() {
+            eprintln!(
+                "Reusing {} of {} tiles from the cache",
+                reused.len(),
+                tile_hashes.len()
+            );
+        }
+
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        let mut fill_indices = Vec::new();
+        let mut lods = Vec::new();
+        for (level, mut lod) in Lod::levels().enumerate() {
+            let visible = |style: u32| {
+                let style = &styles[style as usize];
+                style.min_zoom < lod.max_zoom && style.max_zoom >= lod.min_zoom
+            };
+            // Anything smaller than this would be less than a pixel across
+            let too_small = |(min, max): (DVec2, DVec2)| {
+                lod.tolerance > 0.0 && min.distance(max) < lod.tolerance
+            };
+
+            // Sorted by z first so higher ways still draw on top across tiles
+            let mut batches: BTreeMap<(i32, (i32, i32)), BatchBuilder> = BTreeMap::new();
+            for &(refs, way_style) in &lines {
+                let way_bounds = bounds(refs, &positions);
+                let tile = tile_key(way_bounds, origin);
+                if !visible(way_style) || too_small(way_bounds) || reused.contains(&tile) {
+                    continue;
+                }
+                let key = (z_order(way_style), tile);
+                let batch = batches.entry(key).or_default();
+                batch.extend(way_bounds);
+                batch
+                    .ways
+                    .push((lod::simplify(refs, &positions, lod.tolerance), way_style));
+            }
+            for (outer, holes, area_style) in &areas {
+                let outer_bounds = bounds(outer, &positions);
+                let tile = tile_key(outer_bounds, origin);
+                if !visible(*area_style) || too_small(outer_bounds) || reused.contains(&tile) {
+                    continue;
+                }
+                let outer = lod::simplify(outer, &positions, lod.tolerance);
+                if outer.len() < 4 {
+                    continue;
+                }
+                let holes = holes
+                    .iter()
+                    .map(|hole| lod::simplify(hole, &positions, lod.tolerance))
+                    .filter(|hole| hole.len() >= 4)
+                    .collect();
+                let key = (z_order(*area_style), tile);
+                let batch = batches.entry(key).or_default();
+                batch.extend(outer_bounds);
+                batch.areas.push((outer, holes, *area_style));
+            }
+            let copied: BTreeMap<_, _> = previous
+                .iter()
+                .flat_map(|previous| &previous.lods[level].batches)
+                .filter(|batch| reused.contains(&batch.tile))
+                .map(|batch| ((batch.z_order, batch.tile), batch))
+                .collect();
+            let mut keys: Vec<_> = batches.keys().chain(copied.keys()).copied().collect();
+            keys.sort_unstable();
+
+            for (z_order, tile) in keys {
+                let Some(batch) = batches.remove(&(z_order, tile)) else {
+                    let copy = previous.unwrap().copy_batch(
+                        copied[&(z_order, tile)],
+                        origin,
+                        &mut vertices,
+                        &mut indices,
+                        &mut fill_indices,
+                    );
+                    lod.batches.push(copy);
+                    continue;
+                };
+
+                let vertices_start = vertices.len() as u32;
+                // Ways get their own vertices so a node shared by two ways can take both styles
+                if !indices.is_empty() && !batch.ways.is_empty() {
+                    indices.push(u32::MAX);
+                }
+                let lines_start = indices.len() as u32;
+                for (i, (refs, style)) in batch.ways.into_iter().enumerate() {
+                    if i > 0 {
+                        indices.push(u32::MAX);
+                    }
+                    for node in refs {
+                        indices.push(vertices.len() as u32);
+                        vertices.push(Vertex::new(positions[node], style));
+                    }
+                }
+
+                let fills_start = fill_indices.len() as u32;
+                for (outer, holes, style) in batch.areas {
+                    let holes: Vec<_> = holes.iter().map(Vec::as_slice).collect();
+                    let base = vertices.len() as u32;
+                    let triangles = multipolygon::triangulate(&outer, &holes, &positions);
+                    fill_indices.extend(triangles.into_iter().map(|i| base + i as u32));
+                    // Same order as the coordinates handed to `triangulate`
+                    for ring in std::iter::once(outer.as_slice()).chain(holes) {
+                        vertices.extend(
+                            ring[..ring.len() - 1]
+                                .iter()
+                                .map(|&node| Vertex::new(positions[node], style)),
+                        );
+                    }
+                }
+
+                lod.batches.push(Batch {
+                    z_order,
+                    tile,
+                    min: batch.min.as_vec2(),
+                    max: batch.max.as_vec2(),
+                    vertices: vertices_start..vertices.len() as u32,
+                    lines: lines_start..indices.len() as u32,
+                    fills: fills_start..fill_indices.len() as u32,
+                    layer: 0,
+                });
+            }
+            lods.push(lod);
+        }
+
+        Self {
+            vertices: vertices.into(),
+            indices: indices.into(),
+            fill_indices: fill_indices.into(),
+            lods,
+            styles: styles.to_vec(),
+            projection,
+            origin,
+            tile_hashes,
+        }
     }
 
-    pub fn from_osm(osm: &osm::OSM) -> Self {
-        let vertices = osm.vertices();
-        let indices = osm.indices();
-        Self::new(vertices, indices)
+    // Appends one of the batches to the buffers of render data around `origin`
+    fn copy_batch(
+        &self,
+        batch: &Batch,
+        origin: DVec2,
+        vertices: &mut Vec<Vertex>,
+        indices: &mut Vec<u32>,
+        fill_indices: &mut Vec<u32>,
+    ) -> Batch {
+        let shift = self.origin - origin;
+        let range = |range: &Range<u32>| range.start as usize..range.end as usize;
+        let vertices_start = vertices.len() as u32;
+        vertices.extend(self.vertices[range(&batch.vertices)].iter().map(|vertex| {
+            let pos = vertex.pos.as_dvec2() + vertex.pos_low.as_dvec2() + shift;
+            Vertex::new(pos, vertex.style)
+        }));
+        let moved = |index: u32| index - batch.vertices.start + vertices_start;
+
+        if !indices.is_empty() && !batch.lines.is_empty() {
+            indices.push(u32::MAX);
+        }
+        let lines_start = indices.len() as u32;
+        indices.extend(self.indices[range(&batch.lines)].iter().map(|&index| {
+            if index == u32::MAX {
+                index
+            } else {
+                moved(index)
+            }
+        }));
+        let fills_start = fill_indices.len() as u32;
+        fill_indices.extend(
+            self.fill_indices[range(&batch.fills)]
+                .iter()
+                .map(|&index| moved(index)),
+        );
+
+        Batch {
+            z_order: batch.z_order,
+            tile: batch.tile,
+            min: batch.min + shift.as_vec2(),
+            max: batch.max + shift.as_vec2(),
+            vertices: vertices_start..vertices.len() as u32,
+            lines: lines_start..indices.len() as u32,
+            fills: fills_start..fill_indices.len() as u32,
+            layer: batch.layer,
+        }
     }
 
-    pub fn cache_to(&self, cache_path: impl AsRef<Path>) -> Result<()> {
-        if !cache_path.as_ref().exists() {
-            let bytes = bincode::serialize(&self)?;
-            let bytes: Vec<_> = bytes
-                .into_iter()
-                .encode(&mut ZlibEncoder::new(), Action::Finish)
-                .collect::<Result<_, _>>()?;
-            std::fs::write(cache_path, bytes)?;
+    /// Puts files rendered on their own into one set of buffers, drawn in the order
+    /// given. Each layer gets a copy of the styles mixed with its own color.
+    pub fn layered(layers: Vec<RawRenderData>) -> Self {
+        let origin = layers.iter().map(|layer| layer.origin).sum::<DVec2>() / layers.len() as f64;
+        let projection = layers[0].projection;
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        let mut fill_indices = Vec::new();
+        let mut styles = Vec::new();
+        let mut lods: Vec<_> = Lod::levels().collect();
+        for (i, layer) in layers.into_iter().enumerate() {
+            let shift = layer.origin - origin;
+            let vertex_base = vertices.len() as u32;
+            let style_base = styles.len() as u32;
+            styles.extend(layer.styles.iter().map(|style| style.for_layer(i)));
+            vertices.extend(layer.vertices.iter().map(|vertex| {
+                let pos = vertex.pos.as_dvec2() + vertex.pos_low.as_dvec2() + shift;
+                Vertex::new(pos, vertex.style + style_base)
+            }));
+
+            if !indices.is_empty() && !layer.indices.is_empty() {
+                indices.push(u32::MAX);
+            }
+            let lines_base = indices.len() as u32;
+            indices.extend(layer.indices.iter().map(|&index| {
+                if index == u32::MAX {
+                    index
+                } else {
+                    index + vertex_base
+                }
+            }));
+            let fills_base = fill_indices.len() as u32;
+            fill_indices.extend(layer.fill_indices.iter().map(|&index| index + vertex_base));
+
+            let offset = |range: &Range<u32>, base: u32| range.start + base..range.end + base;
+            for (lod, layer_lod) in lods.iter_mut().zip(&layer.lods) {
+                lod.batches
+                    .extend(layer_lod.batches.iter().map(|batch| Batch {
+                        z_order: batch.z_order,
+                        tile: batch.tile,
+                        min: batch.min + shift.as_vec2(),
+                        max: batch.max + shift.as_vec2(),
+                        vertices: offset(&batch.vertices, vertex_base),
+                        lines: offset(&batch.lines, lines_base),
+                        fills: offset(&batch.fills, fills_base),
+                        layer: i as u32,
+                    }));
+            }
+        }
+
+        Self {
+            vertices: vertices.into(),
+            indices: indices.into(),
+            fill_indices: fill_indices.into(),
+            lods,
+            styles,
+            projection,
+            origin,
+            // Layers can't be updated with change files
+            tile_hashes: HashMap::new(),
         }
-        Ok(())
     }
 
     pub fn sorted(&self, cell_size: f32) -> SortedRenderData {
@@ -97,23 +508,286 @@ impl<'a> SortedRenderData<'a> {
 fn main() -> Result<()> {
     let mut args = std::env::args();
     args.next();
-    let osm_path = args.next().expect("No OSM file provided");
+    let mut osm_paths = Vec::new();
+    let mut layers = false;
+    let mut style_path = None;
+    let mut projection = Projection::default();
+    let mut clip = None;
+    let mut filter = None;
+    let mut filter_source = None;
+    let mut changes = Vec::new();
+    let mut route_profile = None;
+    let mut use_hierarchy = false;
+    let mut isochrone_minutes = None;
+    let mut headless = false;
+    let mut queries = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--style" => style_path = Some(args.next().expect("No style file provided")),
+            "--projection" => {
+                projection = args.next().expect("No projection provided").parse()?;
+            }
+            "--apply" => changes.push(args.next().expect("No change file provided")),
+            "--bbox" => clip = Some(args.next().expect("No bbox provided").parse()?),
+            "--filter" => {
+                let source = args.next().expect("No filter provided");
+                filter = Some(source.parse::<Filter>()?);
+                filter_source = Some(source);
+            }
+            "--poly" => {
+                clip = Some(Clip::load_poly(
+                    args.next().expect("No poly file provided"),
+                )?);
+            }
+            "--layers" => layers = true,
+            "--hierarchy" => use_hierarchy = true,
+            "--headless" => headless = true,
+            "--query" => queries.push(parse_query(&args.next().expect("No query provided"))?),
+            "--isochrone" => {
+                let minutes = args.next().expect("No minutes provided");
+                isochrone_minutes = Some(
+                    minutes
+                        .split(',')
+                        .map(|minutes| minutes.trim().parse::<f64>())
+                        .collect::<Result<Vec<_>, _>>()
+                        .with_context(|| format!("Invalid isochrone minutes `{}`", minutes))?,
+                );
+            }
+            "--route" => {
+                route_profile = Some(
+                    args.next()
+                        .expect("No profile provided")
+                        .parse::<Profile>()?,
+                );
+            }
+            _ => osm_paths.push(arg),
+        }
+    }
+    if osm_paths.is_empty() {
+        anyhow::bail!("No OSM file provided");
+    }
+    if use_hierarchy && route_profile.is_none() {
+        anyhow::bail!("--hierarchy needs a --route profile");
+    }
+    if headless && route_profile.is_none() {
+        anyhow::bail!("--headless needs a --route profile");
+    }
+    if !queries.is_empty() && !headless {
+        anyhow::bail!("--query needs --headless");
+    }
+    if isochrone_minutes.is_some() && route_profile.is_none() {
+        anyhow::bail!("--isochrone needs a --route profile");
+    }
+    if isochrone_minutes
+        .as_ref()
+        .is_some_and(|minutes| minutes.iter().any(|&minutes| minutes <= 0.0))
+    {
+        anyhow::bail!("Isochrone minutes have to be positive");
+    }
+    if layers && !changes.is_empty() {
+        anyhow::bail!("Change files can't be applied to separate layers");
+    }
+    let style = match style_path {
+        Some(path) => StyleSheet::load(path)?,
+        None => StyleSheet::default(),
+    };
     // let cache_path = Path::new("./cache.bin");
-    let cache_path = PathBuf::from(format!("{}.cache", osm_path));
-    let raw_render_data = if !cache_path.exists() {
-        let osm = osm::OSM::load(osm_path)?;
-        let raw_render_data = RawRenderData::from_osm(&osm);
-        // std::fs::write(cache_path, bincode::serialize(&raw_render_data)?)?;
-        raw_render_data
+    // Named after the first file, with the number of others so a different set of
+    // files next to it gets its own cache
+    let base_path = match osm_paths.len() {
+        1 => osm_paths[0].clone(),
+        n => format!("{}+{}", osm_paths[0], n - 1),
+    };
+    let cache_path = PathBuf::from(format!("{}.cache", base_path));
+    let sources = osm_paths
+        .iter()
+        .map(FileStamp::new)
+        .collect::<Result<Vec<_>>>()?;
+    let change_stamps = changes
+        .iter()
+        .map(FileStamp::new)
+        .collect::<Result<Vec<_>>>()?;
+    let cache_key = cache::CacheKey::new(
+        sources.clone(),
+        change_stamps.clone(),
+        cache::Settings {
+            projection,
+            style: style.source().to_owned(),
+            clip: clip.clone(),
+            filter: filter_source.clone(),
+            layers,
+        },
+    );
+    let options = LoadOptions { clip, filter };
+    let dataset_key = DatasetKey { sources };
+    let dataset_path = PathBuf::from(format!("{}.dataset", base_path));
+    if let (true, Some(profile)) = (headless, route_profile) {
+        let osm = load_for_routing(
+            &osm_paths,
+            &changes,
+            &change_stamps,
+            &options,
+            &dataset_path,
+            &dataset_key,
+        )?;
+        let graph = OSMGraph::from_osm(&osm);
+        eprintln!("Road network loaded");
+        let hierarchy = OnceLock::new();
+        if use_hierarchy {
+            let path = PathBuf::from(format!("{}.{}.ch", base_path, profile));
+            let key = HierarchyKey {
+                dataset: dataset_key,
+                changes: change_stamps,
+                clip: options.clip,
+                filter: filter_source,
+                profile,
+                nodes: graph.nodes().len(),
+            };
+            let _ = hierarchy.set(load_hierarchy(&path, &key, &graph));
+        }
+        let mut planner = RoutePlanner::new(
+            Arc::new(OnceLock::from(graph)),
+            Arc::new(hierarchy),
+            profile,
+        );
+        let mut unrouted = 0;
+        for (from, to) in &queries {
+            planner.clear();
+            let routed = planner.click(*from) && planner.click(*to) && planner.route().is_some();
+            let summary = match routed {
+                true => planner.summary(),
+                false => "no route".to_owned(),
+            };
+            println!(
+                "{:.5},{:.5} -> {:.5},{:.5}: {}",
+                from.x, from.y, to.x, to.y, summary
+            );
+            unrouted += usize::from(!routed);
+        }
+        if unrouted > 0 {
+            anyhow::bail!("No route for {} of {} queries", unrouted, queries.len());
+        }
+        return Ok(());
+    }
+    let graph = Arc::new(OnceLock::new());
+    let cached = if cache_path.exists() {
+        cache::read(&cache_path, &cache_key)
+            .inspect_err(|err| eprintln!("Rebuilding cache: {}", err))
+            .ok()
     } else {
-        let bytes = std::fs::read(&cache_path)?;
-        let bytes: Vec<_> = bytes
-            .into_iter()
-            .decode(&mut ZlibDecoder::new())
-            .collect::<Result<_, _>>()?;
-        bincode::deserialize(&bytes)?
+        None
+    };
+    let mut cache_writers = Vec::new();
+    let raw_render_data = match cached {
+        Some(raw_render_data) => Arc::new(raw_render_data),
+        None if layers => {
+            let layers = osm_paths
+                .iter()
+                .map(|path| {
+                    eprintln!("Loading {}", path);
+                    let osm = OSM::load(path, &options)?;
+                    Ok(RawRenderData::from_osm(&osm, &style, projection))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let raw_render_data = Arc::new(RawRenderData::layered(layers));
+            cache_writers.push(cache::write_in_background(
+                cache_path,
+                cache_key,
+                raw_render_data.clone(),
+            ));
+            raw_render_data
+        }
+        None => {
+            let (osm, dataset_writer) = if changes.is_empty() {
+                (Arc::new(OSM::load_all(&osm_paths, &options)?), None)
+            } else {
+                load_with_changes(
+                    &osm_paths,
+                    &changes,
+                    &change_stamps,
+                    &options,
+                    &dataset_path,
+                    &dataset_key,
+                )?
+            };
+            cache_writers.extend(dataset_writer);
+            // After new change files most tiles are the same as in the old cache
+            let previous = if changes.is_empty() || !cache_path.exists() {
+                None
+            } else {
+                cache::read_for_update(&cache_path, &cache_key)
+                    .inspect_err(|err| eprintln!("Rebuilding every tile: {}", err))
+                    .ok()
+            };
+            let raw_render_data = Arc::new(match &previous {
+                Some(previous) => RawRenderData::updated(previous, &osm, &style, projection),
+                None => RawRenderData::from_osm(&osm, &style, projection),
+            });
+            // Unmapped before the new cache is renamed over it, which Windows refuses
+            // while the file is mapped
+            drop(previous);
+            if route_profile.is_some() {
+                let _ = graph.set(OSMGraph::from_osm(&osm));
+            }
+            cache_writers.push(cache::write_in_background(
+                cache_path,
+                cache_key,
+                raw_render_data.clone(),
+            ));
+            raw_render_data
+        }
     };
 
+    // The cache only has what's drawn, so the road network is loaded from the source
+    // files while the map is already up. The contraction hierarchy follows it.
+    let hierarchy = Arc::new(OnceLock::new());
+    if let Some(profile) = route_profile {
+        let (graph, hierarchy) = (graph.clone(), hierarchy.clone());
+        let osm_paths = osm_paths.clone();
+        let (clip, filter) = (options.clip.clone(), filter_source);
+        let hierarchy_path = PathBuf::from(format!("{}.{}.ch", base_path, profile));
+        std::thread::spawn(move || {
+            if graph.get().is_none() {
+                match load_for_routing(
+                    &osm_paths,
+                    &changes,
+                    &change_stamps,
+                    &options,
+                    &dataset_path,
+                    &dataset_key,
+                ) {
+                    Ok(osm) => {
+                        let _ = graph.set(OSMGraph::from_osm(&osm));
+                        eprintln!("Road network loaded");
+                    }
+                    Err(err) => {
+                        eprintln!("Failed to load the road network: {:#}", err);
+                        return;
+                    }
+                }
+            }
+            if use_hierarchy {
+                let graph = graph.get().unwrap();
+                let key = HierarchyKey {
+                    dataset: dataset_key,
+                    changes: change_stamps,
+                    clip,
+                    filter,
+                    profile,
+                    nodes: graph.nodes().len(),
+                };
+                let _ = hierarchy.set(load_hierarchy(&hierarchy_path, &key, graph));
+            }
+        });
+    }
+    let mut planner = route_profile.map(|profile| {
+        let mut planner = RoutePlanner::new(graph, hierarchy, profile);
+        if let Some(minutes) = isochrone_minutes {
+            planner.show_isochrones(minutes);
+        }
+        planner
+    });
+
     // let vertices = raw_render_data.vertices.clone();
     // let sorted = raw_render_data.sorted(0.1);
 
@@ -128,14 +802,17 @@ fn main() -> Result<()> {
     // let stops = indices.iter().filter(|i| **i == std::u32::MAX).count();
     // println!("Loaded {} lines", stops);
     let event_loop = EventLoop::new()?;
-    let window = WindowBuilder::new()
-        .with_title("WGPU OSM View")
-        .build(&event_loop)?;
-    let mut graphics =
-        Graphics::new(window, &raw_render_data.vertices, &raw_render_data.indices).block_on()?;
+    let window = WindowBuilder::new().with_title(TITLE).build(&event_loop)?;
+    let mut graphics = Graphics::new(window, &raw_render_data).block_on()?;
     // let mut current_points = Vec::new();
     // let mut world_pos = Vec2::new(0.0, 0.0);
-    let mut c_controller = camera::CameraController::new(graphics.size_vec());
+    let mut c_controller = camera::CameraController::new(
+        graphics.size_vec(),
+        raw_render_data.projection,
+        raw_render_data.origin,
+    );
+    let mut cursor = Vec2::ZERO;
+    let mut pressed_at = None;
     event_loop.run(move |event, control_flow| {
         if graphics.input(&event) {
             return;
@@ -166,6 +843,7 @@ fn main() -> Result<()> {
                     let size = graphics.size_vec();
                     let screen_pos = Vec2::new(position.x as f32, position.y as f32);
                     c_controller.update(screen_pos, size);
+                    cursor = screen_pos;
                     // world_pos = screen_pos / size;
                     // println!("World pos: {:?}", screen_pos / size);
                 }
@@ -175,11 +853,57 @@ fn main() -> Result<()> {
                             && button == winit::event::MouseButton::Left,
                     );
 
+                    // A left click that didn't drag the map picks a route end
+                    if button == MouseButton::Left {
+                        match state {
+                            ElementState::Pressed => pressed_at = Some(cursor),
+                            ElementState::Released => {
+                                let clicked = pressed_at
+                                    .take()
+                                    .is_some_and(|at| at.distance(cursor) < CLICK_DISTANCE);
+                                if let (true, Some(planner)) = (clicked, &mut planner) {
+                                    if planner.click(c_controller.screen_to_world(cursor)) {
+                                        show_route(planner, &mut graphics, &raw_render_data);
+                                    }
+                                }
+                            }
+                        }
+                    }
+
                     // Reset camera if right mouse button is pressed
                     if state == winit::event::ElementState::Pressed
                         && button == winit::event::MouseButton::Right
                     {
-                        c_controller.camera = camera::Camera::new();
+                        c_controller.reset();
+                    }
+                }
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            logical_key: Key::Named(NamedKey::Escape),
+                            state: ElementState::Pressed,
+                            ..
+                        },
+                    ..
+                } => {
+                    if let Some(planner) = &mut planner {
+                        planner.clear();
+                        show_route(planner, &mut graphics, &raw_render_data);
+                    }
+                }
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            logical_key: Key::Character(key),
+                            state: ElementState::Pressed,
+                            repeat: false,
+                            ..
+                        },
+                    ..
+                } if key.as_str() == "i" => {
+                    if let Some(planner) = &mut planner {
+                        planner.toggle_isochrones();
+                        show_route(planner, &mut graphics, &raw_render_data);
                     }
                 }
                 WindowEvent::MouseWheel { delta, .. } => {
@@ -198,7 +922,281 @@ fn main() -> Result<()> {
         }
     })?;
 
-    raw_render_data.cache_to(cache_path)?;
+    // Closing the window early shouldn't throw away the parse
+    for writer in cache_writers {
+        if !writer.is_finished() {
+            eprintln!("Waiting for the cache to be written");
+        }
+        let _ = writer.join();
+    }
 
     Ok(())
 }
+
+/// Draws the planned route or isochrone over the map and puts its summary in the title
+fn show_route(planner: &RoutePlanner, graphics: &mut Graphics, raw: &RawRenderData) {
+    let (vertices, indices, fill_indices, styles) = planner.overlay(raw.projection, raw.origin);
+    graphics.set_overlay(&vertices, &indices, &fill_indices, &styles);
+    let summary = planner.summary();
+    if summary.is_empty() {
+        graphics.window().set_title(TITLE);
+    } else {
+        graphics
+            .window()
+            .set_title(&format!("{} - {}", TITLE, summary));
+    }
+}
+
+/// Loads `osm_paths` with `changes` applied like for drawing, waiting for the dataset
+/// file to be written if it had to be updated
+fn load_for_routing(
+    osm_paths: &[String],
+    changes: &[String],
+    stamps: &[FileStamp],
+    options: &LoadOptions,
+    dataset_path: &Path,
+    key: &DatasetKey,
+) -> Result<Arc<OSM>> {
+    if changes.is_empty() {
+        return OSM::load_all(osm_paths, options).map(Arc::new);
+    }
+    let (osm, writer) = load_with_changes(osm_paths, changes, stamps, options, dataset_path, key)?;
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+    Ok(osm)
+}
+
+/// `from_lon,from_lat,to_lon,to_lat` for `--query`
+fn parse_query(s: &str) -> Result<(DVec2, DVec2)> {
+    let coords = s
+        .split(',')
+        .map(|coord| coord.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid query `{}`", s))?;
+    let [from_lon, from_lat, to_lon, to_lat] = coords[..] else {
+        anyhow::bail!("Query `{}` needs four coordinates", s);
+    };
+    Ok((DVec2::new(from_lon, from_lat), DVec2::new(to_lon, to_lat)))
+}
+
+/// Reads the contraction hierarchy for `key` from `path`, or builds and saves it when
+/// it's missing or out of date
+fn load_hierarchy(path: &Path, key: &HierarchyKey, graph: &OSMGraph) -> ContractionHierarchy {
+    if path.exists() {
+        match cache::read_hierarchy(path, key) {
+            Ok(hierarchy) => return hierarchy,
+            Err(err) => eprintln!("Rebuilding contraction hierarchy: {}", err),
+        }
+    }
+    let hierarchy = ContractionHierarchy::build(graph, key.profile);
+    if let Err(err) = cache::write_hierarchy(path, key, &hierarchy) {
+        eprintln!("Failed to write contraction hierarchy: {:#}", err);
+    }
+    hierarchy
+}
+
+/// Loads `osm_paths` with `changes` applied in order, then filters and clips it. The
+/// unfiltered result is kept in a dataset file, so when the same base files come with
+/// more change files later only the new ones are applied. The dataset is written on
+/// its own thread when it changed.
+fn load_with_changes(
+    osm_paths: &[String],
+    changes: &[String],
+    stamps: &[FileStamp],
+    options: &LoadOptions,
+    dataset_path: &Path,
+    key: &DatasetKey,
+) -> Result<(Arc<OSM>, Option<JoinHandle<()>>)> {
+    let cached = if dataset_path.exists() {
+        cache::read_dataset(dataset_path, key)
+            .and_then(|(osm, applied)| {
+                if !stamps.starts_with(&applied) {
+                    anyhow::bail!("the change files changed");
+                }
+                Ok((osm, applied))
+            })
+            .inspect_err(|err| eprintln!("Reloading {}: {}", osm_paths.join(", "), err))
+            .ok()
+    } else {
+        None
+    };
+    // Changes can touch anything, so they're applied before filtering and clipping
+    let (mut base, applied) = match cached {
+        Some((osm, applied)) => (osm, applied.len()),
+        None => (OSM::load_all(osm_paths, &LoadOptions::default())?, 0),
+    };
+    for path in &changes[applied..] {
+        eprintln!("Applying {}", path);
+        let change = xml::read_change(Path::new(path))
+            .with_context(|| format!("Failed to read change file {}", path))?;
+        base = base.apply(change);
+    }
+
+    let base = Arc::new(base);
+    let writer = (applied < changes.len()).then(|| {
+        let (base, dataset_path, key) = (base.clone(), dataset_path.to_owned(), key.clone());
+        let stamps = stamps.to_vec();
+        std::thread::spawn(move || {
+            if let Err(err) = cache::write_dataset(&dataset_path, &key, &base, &stamps) {
+                eprintln!("Failed to write dataset: {:#}", err);
+            }
+        })
+    });
+    let osm = if options.clip.is_none() && options.filter.is_none() {
+        base
+    } else {
+        Arc::new(base.restrict(options))
+    };
+    Ok((osm, writer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A road with a lake next to it in each of a row of tiles, `moved` shifts the
+    // middle node of one road
+    fn row_of_tiles(moved: Option<(usize, f64)>) -> OSM {
+        let mut xml = String::from("<osm version=\"0.6\">");
+        for tile in 0..6 {
+            let id = tile as i64 * 10;
+            let lon = tile as f64 * 0.1;
+            let lat = match moved {
+                Some((moved, lat)) if moved == tile => lat,
+                _ => 0.01,
+            };
+            for (node, lon, lat) in [
+                (1, lon, 0.0),
+                (2, lon + 0.01, lat),
+                (3, lon + 0.02, 0.0),
+                (4, lon, 0.02),
+                (5, lon + 0.01, 0.02),
+                (6, lon, 0.03),
+            ] {
+                xml += &format!(r#"<node id="{}" lat="{}" lon="{}"/>"#, id + node, lat, lon);
+            }
+            xml += &format!(
+                r#"<way id="{0}"><nd ref="{1}"/><nd ref="{2}"/><nd ref="{3}"/>
+                    <tag k="highway" v="primary"/></way>
+                <way id="{4}"><nd ref="{5}"/><nd ref="{6}"/><nd ref="{7}"/><nd ref="{5}"/>
+                    <tag k="natural" v="water"/></way>"#,
+                id,
+                id + 1,
+                id + 2,
+                id + 3,
+                id + 1_000,
+                id + 4,
+                id + 5,
+                id + 6,
+            );
+        }
+        xml += "</osm>";
+        OSM::from_xml(&xml, &LoadOptions::default()).unwrap()
+    }
+
+    // Number of vertices in each line strip of the most detailed level
+    fn strip_lengths(render_data: &RawRenderData) -> Vec<usize> {
+        let mut lengths: Vec<_> = render_data.lods[0]
+            .batches
+            .iter()
+            .flat_map(|batch| {
+                render_data.indices[batch.lines.start as usize..batch.lines.end as usize]
+                    .split(|&index| index == u32::MAX)
+                    .map(<[u32]>::len)
+            })
+            .collect();
+        lengths.sort();
+        lengths
+    }
+
+    #[test]
+    fn draws_two_node_pieces_of_split_ways() {
+        let osm = OSM::from_xml(
+            r#"<osm version="0.6">
+                <node id="1" lat="0" lon="0"/>
+                <node id="2" lat="0" lon="0.001"/>
+                <node id="4" lat="0" lon="0.003"/>
+                <node id="5" lat="0" lon="0.004"/>
+                <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="5"/>
+                    <tag k="highway" v="primary"/></way>
+            </osm>"#,
+            &LoadOptions::default(),
+        )
+        .unwrap();
+        let render_data =
+            RawRenderData::from_osm(&osm, &StyleSheet::default(), Projection::default());
+        assert_eq!(strip_lengths(&render_data), [2, 2]);
+    }
+
+    #[test]
+    fn draws_roads_cutting_a_corner_of_the_clip() {
+        // Both nodes are outside, the road only crosses the lower left corner
+        let osm = OSM::from_xml(
+            r#"<osm version="0.6">
+                <node id="1" lat="0.8" lon="-0.5"/>
+                <node id="2" lat="-0.5" lon="0.8"/>
+                <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way>
+            </osm>"#,
+            &LoadOptions {
+                clip: Some("0,0,1,1".parse().unwrap()),
+                filter: None,
+            },
+        )
+        .unwrap();
+        let ends: Vec<_> = osm.ways()[0]
+            .refs
+            .iter()
+            .map(|&node| osm.nodes()[node].pos)
+            .collect();
+        assert_eq!(ends.len(), 2);
+        assert!(ends[0].distance(DVec2::new(0.0, 0.3)) < 1e-9);
+        assert!(ends[1].distance(DVec2::new(0.3, 0.0)) < 1e-9);
+        let render_data =
+            RawRenderData::from_osm(&osm, &StyleSheet::default(), Projection::default());
+        assert_eq!(strip_lengths(&render_data), [2]);
+    }
+
+    #[test]
+    fn updating_gives_the_same_as_building_from_scratch() {
+        let style = StyleSheet::default();
+        let projection = Projection::default();
+        let before = RawRenderData::from_osm(&row_of_tiles(None), &style, projection);
+        // The last road grows past the others into the tile above, which moves the
+        // origin as well
+        let after = row_of_tiles(Some((5, 0.2)));
+        let built = RawRenderData::from_osm(&after, &style, projection);
+        let updated = RawRenderData::updated(&before, &after, &style, projection);
+
+        let unchanged = before
+            .tile_hashes
+            .iter()
+            .filter(|&(tile, hash)| built.tile_hashes.get(tile) == Some(hash))
+            .count();
+        assert_eq!((unchanged, built.tile_hashes.len()), (5, 7));
+        assert_ne!(before.origin, built.origin);
+
+        assert_eq!(updated.origin, built.origin);
+        assert_eq!(updated.tile_hashes, built.tile_hashes);
+        assert_eq!(*updated.indices, *built.indices);
+        assert_eq!(*updated.fill_indices, *built.fill_indices);
+        assert_eq!(updated.vertices.len(), built.vertices.len());
+        for (a, b) in updated.vertices.iter().zip(built.vertices.iter()) {
+            let pos = |vertex: &Vertex| vertex.pos.as_dvec2() + vertex.pos_low.as_dvec2();
+            assert!(pos(a).distance(pos(b)) < 1e-9);
+            assert_eq!(a.style, b.style);
+        }
+        for (a, b) in updated.lods.iter().zip(&built.lods) {
+            let ranges = |lod: &Lod| {
+                lod.batches
+                    .iter()
+                    .map(|batch| {
+                        let ranges = [&batch.vertices, &batch.lines, &batch.fills];
+                        (batch.z_order, batch.tile, ranges.map(Clone::clone))
+                    })
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(ranges(a), ranges(b));
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use glam::DVec2;
+use serde::{Deserialize, Serialize};
+
+/// A multipolygon relation with its member ways stitched into closed rings.
+/// Rings are node indices into `OSM` with the first index repeated at the end.
+#[derive(Serialize, Deserialize)]
+pub struct Multipolygon {
+    pub relation: usize,
+    pub outers: Vec<Vec<usize>>,
+    pub inners: Vec<Vec<usize>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RingError {
+    // A member way that isn't in the file, usually because the extract was clipped
+    MissingWay(i64),
+    // A chain of member ways that never closes, from its first to its last node index
+    Open {
+        role: String,
+        start: usize,
+        end: usize,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BrokenRing {
+    pub relation_id: i64,
+    pub error: RingError,
+}
+
+impl std::fmt::Display for BrokenRing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error {
+            RingError::MissingWay(way) => write!(
+                f,
+                "relation {}: member way {} not found",
+                self.relation_id, way
+            ),
+            RingError::Open { role, start, end } => write!(
+                f,
+                "relation {}: {} ring from node {} to node {} is not closed",
+                self.relation_id, role, start, end
+            ),
+        }
+    }
+}
+
+/// Joins way segments end to end until they close. Returns the closed rings and
+/// whatever chains could not be closed.
+pub fn assemble_rings(mut segments: Vec<Vec<usize>>) -> (Vec<Vec<usize>>, Vec<Vec<usize>>) {
+    let mut rings = Vec::new();
+    let mut open = Vec::new();
+
+    // Index segments by both of their endpoints so each join is a lookup instead of a scan
+    let mut by_end: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (i, segment) in segments.iter().enumerate() {
+        if let (Some(&first), Some(&last)) = (segment.first(), segment.last()) {
+            by_end.entry(first).or_default().push(i);
+            by_end.entry(last).or_default().push(i);
+        }
+    }
+    let mut used = vec![false; segments.len()];
+
+    for start in 0..segments.len() {
+        if used[start] || segments[start].is_empty() {
+            continue;
+        }
+        used[start] = true;
+        let mut current = std::mem::take(&mut segments[start]);
+        // The chain grows at its end, once that's stuck it's turned around to grow the
+        // start as well so a broken ring comes out as a single chain
+        let mut reversed = false;
+        loop {
+            let first = current[0];
+            let last = current[current.len() - 1];
+            if first == last {
+                if current.len() >= 4 {
+                    rings.push(current);
+                } else {
+                    open.push(current);
+                }
+                break;
+            }
+
+            let next = by_end
+                .get(&last)
+                .and_then(|candidates| candidates.iter().copied().find(|&i| !used[i]));
+            let Some(next) = next else {
+                if !reversed {
+                    reversed = true;
+                    current.reverse();
+                    continue;
+                }
+                open.push(current);
+                break;
+            };
+            used[next] = true;
+            let mut segment = std::mem::take(&mut segments[next]);
+            if segment[0] != last {
+                segment.reverse();
+            }
+            current.extend_from_slice(&segment[1..]);
+        }
+    }
+
+    (rings, open)
+}
+
+impl Multipolygon {
+    /// Pairs every outer ring with the inner rings that lie inside of it
+    pub fn polygons(&self, positions: &[DVec2]) -> Vec<(&[usize], Vec<&[usize]>)> {
+        let mut polygons: Vec<_> = self
+            .outers
+            .iter()
+            .map(|outer| (outer.as_slice(), Vec::new()))
+            .collect();
+        for inner in &self.inners {
+            let point = positions[inner[0]];
+            let outer = polygons
+                .iter_mut()
+                .find(|(outer, _)| ring_contains(outer, positions, point));
+            if let Some((_, holes)) = outer {
+                holes.push(inner.as_slice());
+            }
+        }
+        polygons
+    }
+}
+
+// Even-odd ray cast along +x
+fn ring_contains(ring: &[usize], positions: &[DVec2], point: DVec2) -> bool {
+    let mut inside = false;
+    for pair in ring.windows(2) {
+        let (a, b) = (positions[pair[0]], positions[pair[1]]);
+        if (a.y > point.y) != (b.y > point.y) {
+            let x = a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x);
+            if point.x < x {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+/// Triangulates a closed outer ring with its holes. The returned indices point into the
+/// rings' nodes in order, with the repeated closing node of each ring left out.
+pub fn triangulate(outer: &[usize], holes: &[&[usize]], positions: &[DVec2]) -> Vec<usize> {
+    let mut coords = Vec::new();
+    let mut hole_starts = Vec::new();
+    for (i, ring) in std::iter::once(outer)
+        .chain(holes.iter().copied())
+        .enumerate()
+    {
+        if i > 0 {
+            hole_starts.push(coords.len() / 2);
+        }
+        for &node in &ring[..ring.len() - 1] {
+            let pos = positions[node];
+            coords.push(pos.x);
+            coords.push(pos.y);
+        }
+    }
+    earcutr::earcut(&coords, &hole_starts, 2).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joins_reversed_ways_into_a_ring() {
+        let (rings, open) = assemble_rings(vec![vec![0, 1, 2], vec![0, 3], vec![3, 2]]);
+        assert_eq!(rings, [vec![0, 1, 2, 3, 0]]);
+        assert!(open.is_empty());
+    }
+
+    #[test]
+    fn keeps_closed_ways_and_separate_rings_apart() {
+        let (mut rings, open) = assemble_rings(vec![
+            vec![0, 1, 2, 0],
+            vec![3, 4],
+            vec![5, 6, 3],
+            vec![4, 5],
+        ]);
+        rings.sort();
+        assert_eq!(rings, [vec![0, 1, 2, 0], vec![3, 4, 5, 6, 3]]);
+        assert!(open.is_empty());
+    }
+
+    #[test]
+    fn broken_rings_are_left_open() {
+        let (rings, open) = assemble_rings(vec![vec![0, 1, 2], vec![2, 3], vec![4, 0]]);
+        assert!(rings.is_empty());
+        assert_eq!(open, [vec![3, 2, 1, 0, 4]]);
+    }
+
+    #[test]
+    fn rings_without_area_are_left_open() {
+        let (rings, open) = assemble_rings(vec![vec![0, 1], vec![1, 0], vec![2], vec![]]);
+        assert!(rings.is_empty());
+        assert_eq!(open, [vec![0, 1, 0], vec![2]]);
+    }
+}
@@ -1,277 +1,1226 @@
-use crate::vertex::Vertex;
-use glam::{DVec2, Vec2};
-use osmpbf::Element;
+use crate::{
+    clip::Clip,
+    filter::Filter,
+    multipolygon::{assemble_rings, BrokenRing, Multipolygon, RingError},
+    projection::Projection,
+    xml,
+};
+use glam::DVec2;
+use osmpbf::{BlobDecode, BlobReader, Element, RelMemberType};
 use radix_trie::Trie;
-use std::{collections::HashMap, io::BufReader, path::Path};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    time::Instant,
+};
 
 use anyhow::Result;
 
-struct Node {
-    // id: i64,
-    connected_index: usize,
-    pos: DVec2,
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TempNode {
+    pub id: i64,
+    pub pos: DVec2,
+    pub tags: HashMap<String, String>,
 }
 
-pub struct OSMGraph {
-    nodes: Vec<Node>,
-    connected: Vec<Vec<usize>>,
-    buckets: HashMap<(u32, u32), Vec<usize>>,
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Way {
+    pub id: i64,
+    pub tags: HashMap<String, String>,
+    /// Indices into the nodes of the `OSM` the way was loaded from
+    pub refs: Vec<usize>,
 }
 
-impl OSMGraph {
-    pub fn from_osm(osm: &OSM) -> Self {
-        let mut nodes = Vec::new();
-        let mut connected = Vec::new();
-        let mut buckets = HashMap::new();
-        for &TempNode { pos, .. } in osm.nodes.iter() {
-            let connected_index = connected.len();
-            // let id = node.id;
-            // node_map.insert(i, id);
-            let bucket = ((pos.x as u32 / 100) * 100, (pos.y as u32 / 100) * 100);
-            buckets
-                .entry(bucket)
-                .or_insert_with(Vec::new)
-                .push(nodes.len());
-            nodes.push(Node {
-                connected_index,
-                pos,
-            });
-            connected.push(Vec::new());
-        }
-        for way in &osm.ways {
-            for i in 0..way.len() - 1 {
-                let a = way[i];
-                let b = way[i + 1];
-                connected[a].push(b);
-                connected[b].push(a);
-            }
-        }
-        Self {
-            nodes,
-            connected,
-            buckets,
+impl Way {
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).map(String::as_str)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.refs.len() > 3 && self.refs.first() == self.refs.last()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MemberKind {
+    Node,
+    Way,
+    Relation,
+}
+
+impl From<RelMemberType> for MemberKind {
+    fn from(kind: RelMemberType) -> Self {
+        match kind {
+            RelMemberType::Node => MemberKind::Node,
+            RelMemberType::Way => MemberKind::Way,
+            RelMemberType::Relation => MemberKind::Relation,
         }
     }
+}
 
-    fn closest_node(&self, pos: DVec2) -> Option<usize> {
-        let bucket = ((pos.x as u32 / 100) * 100, (pos.y as u32 / 100) * 100);
-        let mut min_distance = f64::INFINITY;
-        let mut closest = None;
-        for &node in self.buckets.get(&bucket).into_iter().flatten() {
-            let distance = self.nodes[node].pos.distance(pos);
-            if distance < min_distance {
-                min_distance = distance;
-                closest = Some(node);
-            }
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Member {
+    pub kind: MemberKind,
+    pub id: i64,
+    pub role: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Relation {
+    pub id: i64,
+    pub tags: HashMap<String, String>,
+    pub members: Vec<Member>,
+}
+
+/// Elements decoded from a single blob or a whole XML file, ways still refer to
+/// nodes by id
+#[derive(Default)]
+pub struct Block {
+    nodes: Vec<TempNode>,
+    ways: Vec<(Way, Vec<i64>)>,
+    relations: Vec<Relation>,
+}
+
+impl Block {
+    /// Ways and relations the filter rejects are dropped right away, nodes are kept
+    /// until it's known which ways use them. Untagged ways could still be members of
+    /// a multipolygon that is kept, so they're left for `OSM::load` to sort out.
+    fn push(&mut self, element: Element, filter: Option<&Filter>) {
+        match element {
+            Element::Node(node) => self.push_node(TempNode {
+                id: node.id(),
+                pos: DVec2::new(node.lon(), node.lat()),
+                tags: owned_tags(node.tags()),
+            }),
+            Element::DenseNode(node) => self.push_node(TempNode {
+                id: node.id(),
+                pos: DVec2::new(node.lon(), node.lat()),
+                tags: owned_tags(node.tags()),
+            }),
+            Element::Way(way) => self.push_way(
+                Way {
+                    id: way.id(),
+                    tags: owned_tags(way.tags()),
+                    refs: Vec::new(),
+                },
+                way.refs().collect(),
+                filter,
+            ),
+            Element::Relation(relation) => self.push_relation(
+                Relation {
+                    id: relation.id(),
+                    tags: owned_tags(relation.tags()),
+                    members: relation
+                        .members()
+                        .map(|member| Member {
+                            role: member.role().unwrap_or_default().to_owned(),
+                            id: member.member_id,
+                            kind: member.member_type.into(),
+                        })
+                        .collect(),
+                },
+                filter,
+            ),
         }
-        closest
-    }
-
-    // Breadth-first search
-    fn plan_path(&self, a: usize, b: usize) -> Option<Vec<usize>> {
-        let mut visited = vec![false; self.nodes.len()];
-        let mut queue = std::collections::VecDeque::new();
-        let mut prev = vec![std::usize::MAX; self.nodes.len()];
-        queue.push_back(a);
-        visited[a] = true;
-        while let Some(node) = queue.pop_front() {
-            if node == b {
-                let mut path = Vec::new();
-                let mut node = b;
-                while node != a {
-                    path.push(node);
-                    node = prev[node];
-                }
-                path.push(a);
-                path.reverse();
-                return Some(path);
-            }
-            for &next in &self.connected[node] {
-                if !visited[next] {
-                    visited[next] = true;
-                    prev[next] = node;
-                    queue.push_back(next);
-                }
-            }
+    }
+
+    pub fn push_node(&mut self, node: TempNode) {
+        self.nodes.push(node);
+    }
+
+    /// `refs` are the node ids, `way.refs` is filled in once all nodes are loaded
+    pub fn push_way(&mut self, way: Way, refs: Vec<i64>, filter: Option<&Filter>) {
+        if way.tags.is_empty() || filter.is_none_or(|filter| filter.matches(&way.tags)) {
+            self.ways.push((way, refs));
         }
-        None
-    }
-
-    // A* pathfinding
-    pub fn plan_path_a_star(&self, a: usize, b: usize) -> Option<Vec<usize>> {
-        let mut visited = vec![false; self.nodes.len()];
-        let mut queue = std::collections::BinaryHeap::new();
-        let mut prev = vec![std::usize::MAX; self.nodes.len()];
-        let mut cost = vec![f64::INFINITY; self.nodes.len()];
-        let mut heuristic = vec![u64::MAX; self.nodes.len()];
-        queue.push(std::cmp::Reverse((0, a)));
-        cost[a] = 0.0;
-        heuristic[a] = self.nodes[a].pos.distance(self.nodes[b].pos) as u64;
-        while let Some(std::cmp::Reverse((_, node))) = queue.pop() {
-            if node == b {
-                let mut path = Vec::new();
-                let mut node = b;
-                while node != a {
-                    path.push(node);
-                    node = prev[node];
-                }
-                path.push(a);
-                path.reverse();
-                return Some(path);
-            }
-            if visited[node] {
-                continue;
-            }
-            visited[node] = true;
-            for &next in &self.connected[node] {
-                let next_cost = cost[node] + self.nodes[node].pos.distance(self.nodes[next].pos);
-                if next_cost < cost[next] {
-                    cost[next] = next_cost;
-                    heuristic[next] =
-                        (next_cost + self.nodes[next].pos.distance(self.nodes[b].pos)) as u64;
-                    prev[next] = node;
-                    queue.push(std::cmp::Reverse((heuristic[next], next)));
-                }
-            }
+    }
+
+    /// Restrictions and area relations are kept even if the filter rejects them, in
+    /// case their member ways pass it
+    pub fn push_relation(&mut self, relation: Relation, filter: Option<&Filter>) {
+        if needs_members(&relation) || filter.is_none_or(|filter| filter.matches(&relation.tags)) {
+            self.relations.push(relation);
         }
-        None
     }
 }
 
-struct TempNode {
-    // id: i64,
-    pos: DVec2,
-    tags: HashMap<String, String>,
+fn owned_tags<'a>(tags: impl Iterator<Item = (&'a str, &'a str)>) -> HashMap<String, String> {
+    tags.map(|(k, v)| (k.to_owned(), v.to_owned())).collect()
 }
 
+/// What had to be fixed up while loading, mostly caused by extracts that were
+/// clipped through the middle of ways
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LoadReport {
+    /// Ways with two or fewer nodes, kept for multipolygon rings
+    pub small_ways: usize,
+    /// Node references to nodes that aren't in the file
+    pub missing_refs: usize,
+    /// Ways with at least one missing node
+    pub affected_ways: usize,
+    /// Ways cut into more than one piece at missing nodes
+    pub split_ways: usize,
+    /// Ways left without two consecutive nodes, so nothing of them was kept
+    pub dropped_ways: usize,
+}
+
+impl std::fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Small ways: {}, missing node refs: {} in {} ways ({} split, {} dropped)",
+            self.small_ways,
+            self.missing_refs,
+            self.affected_ways,
+            self.split_ways,
+            self.dropped_ways
+        )
+    }
+}
+
+/// Contents of an OsmChange file. Everything in `removed` is taken out before the
+/// elements of `block` are added, so created, modified and deleted elements are all
+/// listed there.
+#[derive(Default)]
+pub struct Change {
+    pub block: Block,
+    pub removed: HashSet<(MemberKind, i64)>,
+}
+
+// Prints how long each phase of loading took
+fn timer() -> impl FnMut(&str) {
+    let mut phase_start = Instant::now();
+    move |name| {
+        eprintln!("{} took {:.2?}", name, phase_start.elapsed());
+        phase_start = Instant::now();
+    }
+}
+
+/// What `OSM::load` keeps, by default everything in the file
+#[derive(Clone, Default)]
+pub struct LoadOptions {
+    /// Only keep what's inside this area
+    pub clip: Option<Clip>,
+    /// Only keep elements with matching tags, and the nodes the kept ways need
+    pub filter: Option<Filter>,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct OSM {
     nodes: Vec<TempNode>,
     min: DVec2,
     max: DVec2,
-    ways: Vec<Vec<usize>>,
+    ways: Vec<Way>,
+    relations: Vec<Relation>,
+    multipolygons: Vec<Multipolygon>,
+    broken_rings: Vec<BrokenRing>,
+    report: LoadReport,
 }
 
 impl OSM {
-    pub fn load(path: impl AsRef<Path>) -> Result<OSM> {
-        let reader = std::fs::File::open(path)?;
-        let reader = BufReader::new(reader);
-        let file = osmpbf::ElementReader::new(reader);
-        let mut temp_map = std::collections::HashMap::new();
-        let mut nodes = Vec::new();
-        let mut ways = Vec::new();
+    pub fn load(path: impl AsRef<Path>, options: &LoadOptions) -> Result<OSM> {
+        Self::load_all(&[path], options)
+    }
+
+    /// Loads several files into one dataset, e.g. neighbouring extracts. Elements
+    /// found in more than one file are only kept once.
+    pub fn load_all(paths: &[impl AsRef<Path>], options: &LoadOptions) -> Result<OSM> {
+        let mut phase = timer();
+        let mut blocks = Vec::new();
+        for path in paths {
+            blocks.extend(decode(path.as_ref(), options.filter.as_ref(), &mut phase)?);
+        }
+
+        // Elements are decoded along with the blocks, this only puts them together
+        let mut nodes: Vec<_> = blocks
+            .iter_mut()
+            .flat_map(|block| block.nodes.drain(..))
+            .collect();
+        let mut ways: Vec<_> = blocks
+            .iter_mut()
+            .flat_map(|block| block.ways.drain(..))
+            .collect();
+        let mut relations: Vec<_> = blocks
+            .into_iter()
+            .flat_map(|block| block.relations)
+            .collect();
+        phase("Collecting");
+
+        if paths.len() > 1 {
+            deduplicate(&mut nodes, &mut ways, &mut relations);
+            phase("Deduplicating");
+        }
+
+        Ok(Self::build(nodes, ways, relations, options, &mut phase))
+    }
+
+    /// Builds a dataset from an OSM XML document in memory, like `load` for a file
+    #[cfg(test)]
+    pub fn from_xml(xml: &str, options: &LoadOptions) -> Result<OSM> {
+        let mut block = Block::default();
+        xml::read(xml.as_bytes(), &mut block, options.filter.as_ref())?;
+        let Block {
+            nodes,
+            ways,
+            relations,
+        } = block;
+        Ok(Self::build(nodes, ways, relations, options, &mut timer()))
+    }
+
+    /// Applies an OsmChange on top of what was loaded. Changed ways can use any node
+    /// of the base, so it has to be loaded without a filter or clip, see `restrict`.
+    pub fn apply(self, change: Change) -> OSM {
+        let mut phase = timer();
+        let Change { block, removed } = change;
+        let is_removed = |kind, id| removed.contains(&(kind, id));
+
+        // Back to node ids, since indices change with the nodes
+        let ways: Vec<_> = self
+            .ways
+            .into_iter()
+            .filter(|way| !is_removed(MemberKind::Way, way.id))
+            .map(|way| {
+                let ids = way.refs.iter().map(|&node| self.nodes[node].id).collect();
+                (way, ids)
+            })
+            .chain(block.ways)
+            .collect();
+        let nodes: Vec<_> = self
+            .nodes
+            .into_iter()
+            .filter(|node| !is_removed(MemberKind::Node, node.id))
+            .chain(block.nodes)
+            .collect();
+        let relations: Vec<_> = self
+            .relations
+            .into_iter()
+            .filter(|relation| !is_removed(MemberKind::Relation, relation.id))
+            .chain(block.relations)
+            .collect();
+        phase("Applying changes");
+
+        Self::build(nodes, ways, relations, &LoadOptions::default(), &mut phase)
+    }
+
+    /// Filters and clips a dataset that was loaded without either, which gives the
+    /// same as loading it with `options` in the first place
+    pub fn restrict(&self, options: &LoadOptions) -> OSM {
+        let mut phase = timer();
+        let filter = options.filter.as_ref();
+        let mut block = Block::default();
+        for node in &self.nodes {
+            block.push_node(node.clone());
+        }
+        for way in &self.ways {
+            let ids = way.refs.iter().map(|&node| self.nodes[node].id).collect();
+            let way = Way {
+                id: way.id,
+                tags: way.tags.clone(),
+                refs: Vec::new(),
+            };
+            block.push_way(way, ids, filter);
+        }
+        for relation in &self.relations {
+            block.push_relation(relation.clone(), filter);
+        }
+        phase("Copying");
+
+        Self::build(
+            block.nodes,
+            block.ways,
+            block.relations,
+            options,
+            &mut phase,
+        )
+    }
+
+    /// Turns the node ids of the ways into indices, then filters, clips and puts the
+    /// multipolygons together
+    fn build(
+        nodes: Vec<TempNode>,
+        raw_ways: Vec<(Way, Vec<i64>)>,
+        relations: Vec<Relation>,
+        options: &LoadOptions,
+        phase: &mut impl FnMut(&str),
+    ) -> OSM {
+        let mut nodes = nodes;
+        let mut relations = relations;
+        let mut temp_map = HashMap::with_capacity(nodes.len());
         let mut min = DVec2::new(f64::MAX, f64::MAX);
         let mut max = DVec2::new(f64::MIN, f64::MIN);
-        let mut small = 0;
-        file.for_each(|ele| match ele {
-            Element::Node(node) => {
-                temp_map.insert(node.id(), nodes.len());
-                let pos = DVec2::new(node.lon(), node.lat());
-                // nodes.push(node);
-                min = min.min(pos);
-                max = max.max(pos);
-                nodes.push(TempNode {
-                    pos,
-                    tags: node
-                        .tags()
-                        .map(|(k, v)| (k.to_owned(), v.to_owned()))
-                        .collect(),
-                });
+        for (index, node) in nodes.iter().enumerate() {
+            temp_map.insert(node.id, index);
+            min = min.min(node.pos);
+            max = max.max(node.pos);
+        }
+
+        let mut report = LoadReport {
+            small_ways: raw_ways.iter().filter(|(_, ids)| ids.len() <= 2).count(),
+            ..Default::default()
+        };
+        let remapped: Vec<_> = raw_ways
+            .into_par_iter()
+            .map(|(way, ids)| {
+                let (pieces, missing) = split_at_missing(&ids, &temp_map);
+                (way, pieces, missing)
+            })
+            .collect();
+
+        let mut ways = Vec::with_capacity(remapped.len());
+        for (way, pieces, missing) in remapped {
+            if missing > 0 {
+                report.missing_refs += missing;
+                report.affected_ways += 1;
+                match pieces.len() {
+                    0 => report.dropped_ways += 1,
+                    1 => {}
+                    _ => report.split_ways += 1,
+                }
             }
-            Element::DenseNode(node) => {
-                temp_map.insert(node.id(), nodes.len());
-                let pos = DVec2::new(node.lon(), node.lat());
-                min = min.min(pos);
-                max = max.max(pos);
-                nodes.push(TempNode {
-                    pos,
-                    tags: node
-                        .tags()
-                        .map(|(k, v)| (k.to_owned(), v.to_owned()))
-                        .collect(),
+            // Short ways are kept since multipolygon rings are often stitched from them
+            for refs in pieces {
+                ways.push(Way {
+                    id: way.id,
+                    tags: way.tags.clone(),
+                    refs,
                 });
-                // nodes.push(node);
             }
-            Element::Way(way) => {
-                let ids = way.refs();
-                if ids.len() <= 2 {
-                    small += 1;
-                    // eprintln!("Way with less than 2 nodes");
-                    return;
-                }
-                ways.push(ids.collect());
+        }
+        phase("Index remap");
+
+        if let Some(filter) = &options.filter {
+            // Untagged ways only stay as members of relations that passed the filter
+            let members: HashSet<_> = relations
+                .iter()
+                .filter(|relation| filter.matches(&relation.tags))
+                .flat_map(|relation| &relation.members)
+                .filter(|member| member.kind == MemberKind::Way)
+                .map(|member| member.id)
+                .collect();
+            ways.retain(|way| !way.tags.is_empty() || members.contains(&way.id));
+            // Relations that only got past the filter for their members stay if all of
+            // their member ways did, e.g. turn restrictions between kept roads
+            let kept_ways: HashSet<_> = ways.iter().map(|way| way.id).collect();
+            let restrictions_before = relations
+                .iter()
+                .filter(|relation| is_restriction(relation))
+                .count();
+            relations.retain(|relation| {
+                let mut members = relation
+                    .members
+                    .iter()
+                    .filter(|member| member.kind == MemberKind::Way)
+                    .peekable();
+                filter.matches(&relation.tags)
+                    || members.peek().is_some()
+                        && members.all(|member| kept_ways.contains(&member.id))
+            });
+            let tagged = nodes
+                .iter()
+                .map(|node| !node.tags.is_empty() && filter.matches(&node.tags))
+                .collect();
+            retain_nodes(&mut nodes, &mut ways, tagged);
+            min = nodes.iter().fold(DVec2::MAX, |min, node| min.min(node.pos));
+            max = nodes.iter().fold(DVec2::MIN, |max, node| max.max(node.pos));
+            eprintln!(
+                "Filtered to {} nodes, {} ways, {} relations",
+                nodes.len(),
+                ways.len(),
+                relations.len()
+            );
+            let restrictions_after = relations
+                .iter()
+                .filter(|relation| is_restriction(relation))
+                .count();
+            if restrictions_before > 0 && restrictions_after == 0 {
+                eprintln!(
+                    "Warning: the filter dropped all {} turn restrictions, routes may break them",
+                    restrictions_before
+                );
+            } else if restrictions_after < restrictions_before {
+                eprintln!(
+                    "Filter dropped {} of {} turn restrictions",
+                    restrictions_before - restrictions_after,
+                    restrictions_before
+                );
             }
-            _ => {}
-        })?;
-        eprintln!("Small ways: {}", small);
+            phase("Filtering");
+        }
 
-        // Go through the ways and replace the ids with the indices
-        let ways = ways
-            .into_iter()
-            .map(|way: Vec<_>| {
-                way.into_iter()
-                    .map(|id| temp_map.get(&id).copied().unwrap())
-                    .collect()
-            })
-            .collect();
+        if let Some(clip) = &options.clip {
+            clip_to(clip, &mut nodes, &mut ways, &mut relations);
+            let (clip_min, clip_max) = clip.bounds();
+            min = nodes.iter().fold(DVec2::MAX, |min, node| min.min(node.pos));
+            max = nodes.iter().fold(DVec2::MIN, |max, node| max.max(node.pos));
+            // Ways kept whole can reach far outside, the view should center on the clip
+            min = min.max(clip_min);
+            max = max.min(clip_max);
+            eprintln!(
+                "Clipped to {} nodes, {} ways, {} relations",
+                nodes.len(),
+                ways.len(),
+                relations.len()
+            );
+            phase("Clipping");
+        }
 
-        Ok(OSM {
+        let (multipolygons, broken_rings) = assemble_multipolygons(&relations, &ways);
+        phase("Multipolygons");
+        eprintln!(
+            "Relations: {}, multipolygons: {}",
+            relations.len(),
+            multipolygons.len()
+        );
+        for broken in &broken_rings {
+            eprintln!("Broken ring in {}", broken);
+        }
+        eprintln!("{}", report);
+
+        OSM {
             nodes,
             ways,
             min,
             max,
+            relations,
+            multipolygons,
+            broken_rings,
+            report,
+        }
+    }
+
+    pub fn nodes(&self) -> &[TempNode] {
+        &self.nodes
+    }
+
+    pub fn ways(&self) -> &[Way] {
+        &self.ways
+    }
+
+    pub fn relations(&self) -> &[Relation] {
+        &self.relations
+    }
+
+    pub fn multipolygons(&self) -> &[Multipolygon] {
+        &self.multipolygons
+    }
+
+    /// Rings of multipolygon relations that couldn't be closed while loading
+    pub fn broken_rings(&self) -> &[BrokenRing] {
+        &self.broken_rings
+    }
+
+    pub fn report(&self) -> &LoadReport {
+        &self.report
+    }
+
+    /// The projected center of the data, which `positions` are relative to
+    pub fn origin(&self, projection: Projection) -> DVec2 {
+        projection.project((self.min + self.max) / 2.0)
+    }
+
+    pub fn positions(&self, projection: Projection) -> Vec<DVec2> {
+        let origin = self.origin(projection);
+        self.nodes
+            .iter()
+            .map(|node| projection.project(node.pos) - origin)
+            .collect()
+    }
+
+    pub fn trie(&mut self) -> Trie<String, usize> {
+        let mut trie = Trie::new();
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            if let Some(name) = node.tags.remove("name") {
+                trie.insert(name, i);
+            }
+        }
+        trie
+    }
+}
+
+fn decode(
+    path: &Path,
+    filter: Option<&Filter>,
+    phase: &mut impl FnMut(&str),
+) -> Result<Vec<Block>> {
+    match xml::open(path)? {
+        Some(reader) => {
+            let mut block = Block::default();
+            xml::read(reader, &mut block, filter)?;
+            phase("Parsing XML");
+            Ok(vec![block])
+        }
+        None => {
+            // Blobs are independent so they're decoded on every core, then put back in
+            // file order so the indices come out the same on every run
+            let mut blocks = BlobReader::from_path(path)?
+                .enumerate()
+                .par_bridge()
+                .map(|(i, blob)| -> Result<_> {
+                    let mut block = Block::default();
+                    if let BlobDecode::OsmData(data) = blob?.decode()? {
+                        data.for_each_element(|element| block.push(element, filter));
+                    }
+                    Ok((i, block))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            blocks.sort_unstable_by_key(|&(i, _)| i);
+            phase("Decoding blobs");
+            Ok(blocks.into_iter().map(|(_, block)| block).collect())
+        }
+    }
+}
+
+/// Neighbouring extracts both contain what lies along their border. Nodes are the
+/// same in both, but a way or relation can be cut short in one of them, so the copy
+/// with the most nodes or members is kept.
+fn deduplicate(
+    nodes: &mut Vec<TempNode>,
+    ways: &mut Vec<(Way, Vec<i64>)>,
+    relations: &mut Vec<Relation>,
+) {
+    let before = (nodes.len(), ways.len(), relations.len());
+    let mut seen = HashSet::with_capacity(nodes.len());
+    nodes.retain(|node| seen.insert(node.id));
+    keep_largest(ways, |(way, _)| way.id, |(_, refs)| refs.len());
+    keep_largest(
+        relations,
+        |relation| relation.id,
+        |relation| relation.members.len(),
+    );
+    eprintln!(
+        "Duplicates: {} nodes, {} ways, {} relations",
+        before.0 - nodes.len(),
+        before.1 - ways.len(),
+        before.2 - relations.len()
+    );
+}
+
+fn keep_largest<T>(items: &mut Vec<T>, id: impl Fn(&T) -> i64, size: impl Fn(&T) -> usize) {
+    let mut largest = HashMap::with_capacity(items.len());
+    for (index, item) in items.iter().enumerate() {
+        largest
+            .entry(id(item))
+            .and_modify(|largest: &mut usize| {
+                if size(item) > size(&items[*largest]) {
+                    *largest = index;
+                }
+            })
+            .or_insert(index);
+    }
+    let mut index = 0;
+    items.retain(|item| {
+        let keep = largest[&id(item)] == index;
+        index += 1;
+        keep
+    });
+}
+
+fn is_area_relation(relation: &Relation) -> bool {
+    matches!(
+        relation.tags.get("type").map(String::as_str),
+        Some("multipolygon") | Some("boundary")
+    )
+}
+
+// Relations that mean nothing without their member ways
+fn needs_members(relation: &Relation) -> bool {
+    is_area_relation(relation) || is_restriction(relation)
+}
+
+fn is_restriction(relation: &Relation) -> bool {
+    relation.tags.get("type").map(String::as_str) == Some("restriction")
+}
+
+/// Nodes made where ways cross the clip boundary get ids counting up from `i64::MIN`,
+/// far below any real id
+const SYNTHETIC_IDS: i64 = i64::MIN / 2;
+
+/// Keeps what's inside `clip`. Open ways are cut where they cross the boundary, with
+/// new nodes at the crossings. Closed ways and the members of area relations that
+/// touch the area are kept whole instead so they can still be filled.
+fn clip_to(
+    clip: &Clip,
+    nodes: &mut Vec<TempNode>,
+    ways: &mut Vec<Way>,
+    relations: &mut Vec<Relation>,
+) {
+    let mut inside: Vec<_> = nodes
+        .par_iter()
+        .map(|node| clip.contains(node.pos))
+        .collect();
+    // Segments with both ends inside are assumed to stay inside, which only misses
+    // a concave boundary poking between two nodes
+    let crosses = |a: usize, b: usize| {
+        (!inside[a] || !inside[b]) && !clip.crossings(nodes[a].pos, nodes[b].pos).is_empty()
+    };
+    let touches: Vec<_> = ways
+        .par_iter()
+        .map(|way| {
+            way.refs.iter().any(|&node| inside[node])
+                || way.refs.windows(2).any(|pair| crosses(pair[0], pair[1]))
         })
+        .collect();
+
+    let mut whole_ids = HashSet::new();
+    for (way, &touches) in ways.iter().zip(&touches) {
+        if touches && way.is_closed() {
+            whole_ids.insert(way.id);
+        }
+    }
+    let touching_ids: HashSet<_> = ways
+        .iter()
+        .zip(&touches)
+        .filter_map(|(way, &touches)| touches.then_some(way.id))
+        .collect();
+    for relation in relations
+        .iter()
+        .filter(|relation| is_area_relation(relation))
+    {
+        let members = relation
+            .members
+            .iter()
+            .filter(|member| member.kind == MemberKind::Way);
+        if members
+            .clone()
+            .any(|member| touching_ids.contains(&member.id))
+        {
+            whole_ids.extend(members.map(|member| member.id));
+        }
+    }
+
+    let mut next_id = nodes
+        .iter()
+        .map(|node| node.id)
+        .filter(|&id| id < SYNTHETIC_IDS)
+        .max()
+        .map_or(i64::MIN, |id| id + 1);
+    let mut clipped = Vec::new();
+    for way in ways.drain(..) {
+        if whole_ids.contains(&way.id) {
+            clipped.push(way);
+            continue;
+        }
+        let mut pieces = Vec::new();
+        let mut piece = Vec::new();
+        if way.refs.first().is_some_and(|&node| inside[node]) {
+            piece.push(way.refs[0]);
+        }
+        for pair in way.refs.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            let mut in_clip = inside[a];
+            if !(inside[a] && inside[b]) {
+                let (pos_a, pos_b) = (nodes[a].pos, nodes[b].pos);
+                for t in clip.crossings(pos_a, pos_b) {
+                    piece.push(nodes.len());
+                    nodes.push(TempNode {
+                        id: next_id,
+                        pos: pos_a.lerp(pos_b, t),
+                        tags: HashMap::new(),
+                    });
+                    next_id += 1;
+                    inside.push(true);
+                    if in_clip {
+                        pieces.push(std::mem::take(&mut piece));
+                    }
+                    in_clip = !in_clip;
+                }
+            }
+            if inside[b] {
+                piece.push(b);
+            } else if !piece.is_empty() {
+                // Rounding can miss a crossing right at a node
+                pieces.push(std::mem::take(&mut piece));
+            }
+        }
+        pieces.push(piece);
+        for refs in pieces.into_iter().filter(|piece| piece.len() >= 2) {
+            clipped.push(Way {
+                id: way.id,
+                tags: way.tags.clone(),
+                refs,
+            });
+        }
     }
+    *ways = clipped;
+
+    retain_nodes(nodes, ways, inside);
 
-    pub fn indices(&self) -> Vec<u32> {
-        let mut indices = Vec::new();
-        for (i, way) in self.ways.iter().enumerate() {
-            indices.extend(way.iter().map(|&x| x as u32));
-            if i != self.ways.len() - 1 {
-                indices.push(std::u32::MAX);
+    // Relations stay if any member did, members that are relations themselves are
+    // only known once those have been decided
+    let mut kept: HashSet<_> = ways
+        .iter()
+        .map(|way| (MemberKind::Way, way.id))
+        .chain(nodes.iter().map(|node| (MemberKind::Node, node.id)))
+        .collect();
+    let mut undecided: Vec<_> = relations.iter().collect();
+    loop {
+        let before = undecided.len();
+        undecided.retain(|relation| {
+            let keep = relation
+                .members
+                .iter()
+                .any(|member| kept.contains(&(member.kind, member.id)));
+            if keep {
+                kept.insert((MemberKind::Relation, relation.id));
             }
+            !keep
+        });
+        if undecided.len() == before {
+            break;
         }
-        indices
     }
+    relations.retain(|relation| kept.contains(&(MemberKind::Relation, relation.id)));
+}
 
-    pub fn vertices(&self) -> Vec<Vertex> {
-        let mut vertices = Vec::new();
-        let size = self.size();
-        let center = self.center();
-        for node in &self.nodes {
-            vertices.push(Vertex {
-                pos: node.pos.as_vec2() - center / size,
-                // pos: Vec2::new((node.x - center.x) / size.x, (node.y - center.y) / size.y),
+/// Drops the nodes that no way uses and that aren't marked in `keep`, pointing the
+/// ways at the new indices
+fn retain_nodes(nodes: &mut Vec<TempNode>, ways: &mut [Way], keep: Vec<bool>) {
+    let mut used = keep;
+    for &node in ways.iter().flat_map(|way| &way.refs) {
+        used[node] = true;
+    }
+    let mut remap = vec![usize::MAX; nodes.len()];
+    let mut kept = 0;
+    for (index, &used) in used.iter().enumerate() {
+        if used {
+            remap[index] = kept;
+            kept += 1;
+        }
+    }
+    let mut used = used.into_iter();
+    nodes.retain(|_| used.next().unwrap());
+    for way in ways.iter_mut() {
+        for node in &mut way.refs {
+            *node = remap[*node];
+        }
+    }
+}
+
+/// Replaces the node ids of a way with indices. Extracts clipped through a way leave
+/// refs to nodes that aren't in the file, so those ways are cut into the runs in
+/// between and runs of a single node are dropped. Also returns how many refs were
+/// missing.
+fn split_at_missing(ids: &[i64], indices: &HashMap<i64, usize>) -> (Vec<Vec<usize>>, usize) {
+    let mut pieces = vec![Vec::new()];
+    let mut missing = 0;
+    for id in ids {
+        match indices.get(id) {
+            Some(&index) => pieces.last_mut().unwrap().push(index),
+            None => {
+                missing += 1;
+                if !pieces.last().unwrap().is_empty() {
+                    pieces.push(Vec::new());
+                }
+            }
+        }
+    }
+    if missing > 0 {
+        pieces.retain(|piece| piece.len() >= 2);
+    }
+    (pieces, missing)
+}
+
+fn assemble_multipolygons(
+    relations: &[Relation],
+    ways: &[Way],
+) -> (Vec<Multipolygon>, Vec<BrokenRing>) {
+    // Ways split at missing nodes or cut by the clip come in several pieces, relations
+    // get all of them
+    let mut way_ids: HashMap<i64, Vec<usize>> = HashMap::new();
+    for (index, way) in ways.iter().enumerate() {
+        way_ids.entry(way.id).or_default().push(index);
+    }
+    let mut multipolygons = Vec::new();
+    let mut broken = Vec::new();
+    for (index, relation) in relations.iter().enumerate() {
+        if !is_area_relation(relation) {
+            continue;
+        }
+        let mut outer = Vec::new();
+        let mut inner = Vec::new();
+        for member in &relation.members {
+            if member.kind != MemberKind::Way {
+                continue;
+            }
+            let Some(pieces) = way_ids.get(&member.id) else {
+                broken.push(BrokenRing {
+                    relation_id: relation.id,
+                    error: RingError::MissingWay(member.id),
+                });
+                continue;
+            };
+            // Old data often leaves the role empty, which is treated as outer
+            let rings = match member.role.as_str() {
+                "inner" => &mut inner,
+                "outer" | "" => &mut outer,
+                _ => continue,
+            };
+            rings.extend(pieces.iter().map(|&way| ways[way].refs.clone()));
+        }
+
+        let (outers, open_outers) = assemble_rings(outer);
+        let (inners, open_inners) = assemble_rings(inner);
+        let open = open_outers
+            .into_iter()
+            .map(|ring| ("outer", ring))
+            .chain(open_inners.into_iter().map(|ring| ("inner", ring)));
+        for (role, ring) in open {
+            broken.push(BrokenRing {
+                relation_id: relation.id,
+                error: RingError::Open {
+                    role: role.to_owned(),
+                    start: ring[0],
+                    end: ring[ring.len() - 1],
+                },
+            });
+        }
+
+        if !outers.is_empty() {
+            multipolygons.push(Multipolygon {
+                relation: index,
+                outers,
+                inners,
             });
         }
-        vertices
     }
+    (multipolygons, broken)
+}
 
-    fn size(&self) -> Vec2 {
-        Vec2::new(self.width(), self.height())
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    // Writes a change file for a test to read, which the test removes again
+    fn temp_file(contents: &str) -> std::path::PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "osm-view-osm-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path
     }
 
-    fn width(&self) -> f32 {
-        (self.max.x - self.min.x) as f32
+    fn load(elements: &str, options: &LoadOptions) -> OSM {
+        OSM::from_xml(&format!("<osm version=\"0.6\">{}</osm>", elements), options).unwrap()
     }
 
-    fn height(&self) -> f32 {
-        (self.max.y - self.min.y) as f32
+    #[test]
+    fn splits_ways_at_missing_nodes() {
+        let indices: HashMap<_, _> = [(1, 0), (2, 1), (3, 2), (5, 3), (6, 4)].into();
+        assert_eq!(
+            split_at_missing(&[1, 2, 3], &indices),
+            (vec![vec![0, 1, 2]], 0)
+        );
+        assert_eq!(
+            split_at_missing(&[1, 2, 4, 5, 6], &indices),
+            (vec![vec![0, 1], vec![3, 4]], 1)
+        );
+        // Runs of a single node can't be drawn
+        assert_eq!(
+            split_at_missing(&[4, 1, 4, 2, 3, 4, 4, 6], &indices),
+            (vec![vec![1, 2]], 4)
+        );
+        assert_eq!(split_at_missing(&[4, 1, 7], &indices), (vec![], 2));
+        // Short ways without missing nodes stay for multipolygon rings
+        assert_eq!(split_at_missing(&[1], &indices), (vec![vec![0]], 0));
     }
 
-    fn center(&self) -> Vec2 {
-        Vec2::new(
-            (self.min.x + self.max.x) as f32 / 2.0,
-            (self.min.y + self.max.y) as f32 / 2.0,
-        )
+    #[test]
+    fn relations_see_every_piece_of_a_split_way() {
+        // The second way of the ring lost node 4, leaving pieces 3-6 and 5-1
+        let osm = load(
+            r#"
+            <node id="1" lat="0.0" lon="0.0"/>
+            <node id="2" lat="0.0" lon="0.001"/>
+            <node id="3" lat="0.001" lon="0.001"/>
+            <node id="5" lat="0.001" lon="0.0"/>
+            <node id="6" lat="0.002" lon="0.001"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/></way>
+            <way id="11"><nd ref="3"/><nd ref="6"/><nd ref="4"/><nd ref="5"/><nd ref="1"/></way>
+            <relation id="20">
+                <member type="way" ref="10" role="outer"/>
+                <member type="way" ref="11" role="outer"/>
+                <tag k="type" v="multipolygon"/>
+            </relation>
+            "#,
+            &LoadOptions::default(),
+        );
+        assert_eq!(osm.report().split_ways, 1);
+        assert!(osm.multipolygons().is_empty());
+        // A single chain through both pieces, open only where the node is missing
+        let [broken] = osm.broken_rings() else {
+            panic!("expected one broken ring");
+        };
+        let RingError::Open { start, end, .. } = broken.error else {
+            panic!("expected an open ring");
+        };
+        let mut ends = [osm.nodes()[start].id, osm.nodes()[end].id];
+        ends.sort();
+        assert_eq!(ends, [5, 6]);
     }
 
-    pub fn trie(&mut self) -> Trie<String, usize> {
-      let mut trie = Trie::new();
-      for (i, node) in self.nodes.iter_mut().enumerate() {
-        if let Some(name) = node.tags.remove("name") {
-          trie.insert(name, i);
-        }
-      }
-      trie
-  }
+    // A road running east through the bbox 0,0,0.002,0.002 and out the other side,
+    // a closed way straddling its west edge and a tagged node on either side
+    const CLIPPED: &str = r#"
+        <node id="1" lat="0.001" lon="-0.001"/>
+        <node id="2" lat="0.001" lon="0.001"/>
+        <node id="3" lat="0.001" lon="0.003"/>
+        <node id="4" lat="0.0015" lon="-0.001"/>
+        <node id="5" lat="0.0015" lon="0.001"/>
+        <node id="6" lat="0.0018" lon="0.001"/>
+        <node id="7" lat="0.0005" lon="0.001"><tag k="amenity" v="bench"/></node>
+        <node id="8" lat="0.0005" lon="-0.001"><tag k="amenity" v="bench"/></node>
+        <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><tag k="highway" v="residential"/></way>
+        <way id="11"><nd ref="4"/><nd ref="5"/><nd ref="6"/><nd ref="4"/><tag k="building" v="yes"/></way>
+        <relation id="20"><member type="node" ref="7" role=""/><tag k="type" v="site"/></relation>
+        <relation id="21"><member type="node" ref="8" role=""/><tag k="type" v="site"/></relation>
+        <relation id="22"><member type="relation" ref="20" role=""/><tag k="type" v="site"/></relation>
+        <relation id="23"><member type="relation" ref="21" role=""/><tag k="type" v="site"/></relation>
+    "#;
+
+    fn bbox() -> LoadOptions {
+        LoadOptions {
+            clip: Some("0,0,0.002,0.002".parse().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn clips_open_ways_at_the_boundary() {
+        let osm = load(CLIPPED, &bbox());
+        let road = osm.ways().iter().find(|way| way.id == 10).unwrap();
+        let positions: Vec<_> = road
+            .refs
+            .iter()
+            .map(|&node| osm.nodes()[node].pos)
+            .collect();
+        assert_eq!(positions.len(), 3);
+        assert!((positions[0] - DVec2::new(0.0, 0.001)).length() < 1e-12);
+        assert_eq!(positions[1], DVec2::new(0.001, 0.001));
+        assert!((positions[2] - DVec2::new(0.002, 0.001)).length() < 1e-12);
+        // Closed ways are kept whole so they can still be filled
+        let building = osm.ways().iter().find(|way| way.id == 11).unwrap();
+        assert_eq!(building.refs.len(), 4);
+        assert!(!osm.nodes().iter().any(|node| [1, 3, 8].contains(&node.id)));
+    }
+
+    #[test]
+    fn keeps_relations_with_any_member_inside() {
+        let osm = load(CLIPPED, &bbox());
+        let mut relations: Vec<_> = osm.relations().iter().map(|relation| relation.id).collect();
+        relations.sort();
+        assert_eq!(relations, [20, 22]);
+    }
+
+    #[test]
+    fn synthetic_ids_stay_unique_when_clipping_again() {
+        let osm = load(CLIPPED, &LoadOptions::default());
+        let OSM {
+            mut nodes,
+            mut ways,
+            mut relations,
+            ..
+        } = osm;
+        let synthetic = |nodes: &[TempNode]| -> HashSet<_> {
+            nodes
+                .iter()
+                .map(|node| node.id)
+                .filter(|&id| id < SYNTHETIC_IDS)
+                .collect()
+        };
+        let bbox = "0,0,0.002,0.002".parse().unwrap();
+        clip_to(&bbox, &mut nodes, &mut ways, &mut relations);
+        let first = synthetic(&nodes);
+        assert_eq!(first.len(), 2);
+        // The first crossings are outside now, so fewer nodes are left than there were
+        let narrower = "0.0005,0,0.0015,0.002".parse().unwrap();
+        clip_to(&narrower, &mut nodes, &mut ways, &mut relations);
+        let second = synthetic(&nodes);
+        assert_eq!(second.len(), 2);
+        assert!(first.is_disjoint(&second));
+    }
+
+    #[test]
+    fn keeps_relations_whose_members_pass_the_filter() {
+        let osm = load(
+            r#"
+            <node id="1" lat="0.0" lon="0.0"/>
+            <node id="2" lat="0.0" lon="0.001"/>
+            <node id="3" lat="0.001" lon="0.001"/>
+            <node id="4" lat="0.001" lon="0.0"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way>
+            <way id="11"><nd ref="2"/><nd ref="3"/><tag k="highway" v="primary"/></way>
+            <way id="12"><nd ref="2"/><nd ref="4"/><tag k="railway" v="rail"/></way>
+            <way id="13"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="1"/>
+                <tag k="highway" v="pedestrian"/></way>
+            <way id="14"><nd ref="1"/><nd ref="3"/><nd ref="4"/><nd ref="1"/></way>
+            <relation id="20">
+                <member type="way" ref="10" role="from"/>
+                <member type="node" ref="2" role="via"/>
+                <member type="way" ref="11" role="to"/>
+                <tag k="type" v="restriction"/><tag k="restriction" v="no_left_turn"/>
+            </relation>
+            <relation id="21">
+                <member type="way" ref="10" role="from"/>
+                <member type="node" ref="2" role="via"/>
+                <member type="way" ref="12" role="to"/>
+                <tag k="type" v="restriction"/><tag k="restriction" v="no_right_turn"/>
+            </relation>
+            <relation id="22">
+                <member type="way" ref="13" role="outer"/>
+                <tag k="type" v="multipolygon"/>
+            </relation>
+            <relation id="23">
+                <member type="way" ref="14" role="outer"/>
+                <tag k="type" v="multipolygon"/><tag k="landuse" v="grass"/>
+            </relation>
+            <relation id="24">
+                <member type="node" ref="1" role=""/>
+                <tag k="type" v="restriction"/>
+            </relation>
+            "#,
+            &LoadOptions {
+                filter: Some("highway".parse().unwrap()),
+                ..Default::default()
+            },
+        );
+        let relations: Vec<_> = osm.relations().iter().map(|relation| relation.id).collect();
+        assert_eq!(relations, [20, 22]);
+        assert_eq!(osm.multipolygons().len(), 1);
+        assert!(osm.ways().iter().all(|way| way.id != 14));
+    }
+
+    #[test]
+    fn keeps_the_largest_copy_of_duplicates() {
+        let mut items = vec![(1, 2), (2, 1), (1, 3), (2, 1), (1, 1)];
+        keep_largest(&mut items, |&(id, _)| id, |&(_, size)| size);
+        assert_eq!(items, [(2, 1), (1, 3)]);
+    }
+
+    #[test]
+    fn merged_files_keep_the_longer_copy_of_a_way() {
+        // The first extract ends at node 2, so it only has part of the road
+        let clipped = temp_file(
+            r#"<osm version="0.6">
+                <node id="1" lat="0" lon="0"/>
+                <node id="2" lat="0" lon="0.001"/>
+                <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way>
+            </osm>"#,
+        );
+        let whole = temp_file(
+            r#"<osm version="0.6">
+                <node id="1" lat="0" lon="0"/>
+                <node id="2" lat="0" lon="0.001"/>
+                <node id="3" lat="0" lon="0.002"/>
+                <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/>
+                    <tag k="highway" v="primary"/></way>
+            </osm>"#,
+        );
+        for paths in [[&clipped, &whole], [&whole, &clipped]] {
+            let osm = OSM::load_all(&paths, &LoadOptions::default()).unwrap();
+            assert_eq!(osm.nodes().len(), 3);
+            assert_eq!(osm.ways().len(), 1);
+            assert_eq!(osm.ways()[0].refs.len(), 3);
+        }
+        std::fs::remove_file(&clipped).unwrap();
+        std::fs::remove_file(&whole).unwrap();
+    }
+
+    // Roads 10 and 11 meet at node 2, stream 12 runs from there to the bench at node 4
+    const BEFORE: &str = r#"
+        <node id="1" lat="0.0" lon="0.0"/>
+        <node id="2" lat="0.0" lon="0.001"/>
+        <node id="3" lat="0.0" lon="0.002"/>
+        <node id="4" lat="0.001" lon="0.001"><tag k="amenity" v="bench"/></node>
+        <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+        <way id="11"><nd ref="2"/><nd ref="3"/><tag k="highway" v="residential"/></way>
+        <way id="12"><nd ref="2"/><nd ref="4"/><tag k="waterway" v="stream"/></way>
+    "#;
+
+    // Moves node 2 and deletes road 11, turns the stream into a road and adds a path
+    // from the bench out of the bbox used below
+    const CHANGE: &str = r#"<osmChange version="0.6">
+        <create>
+            <node id="5" lat="0.003" lon="0.001"/>
+            <way id="13"><nd ref="4"/><nd ref="5"/><tag k="highway" v="path"/></way>
+        </create>
+        <modify>
+            <node id="2" lat="0.0005" lon="0.001"/>
+            <way id="12"><nd ref="2"/><nd ref="4"/><tag k="highway" v="service"/></way>
+        </modify>
+        <delete>
+            <way id="11"/>
+            <node id="3" lat="0.0" lon="0.002"/>
+        </delete>
+    </osmChange>"#;
+
+    // `BEFORE` with `CHANGE` applied
+    const AFTER: &str = r#"
+        <node id="1" lat="0.0" lon="0.0"/>
+        <node id="2" lat="0.0005" lon="0.001"/>
+        <node id="4" lat="0.001" lon="0.001"><tag k="amenity" v="bench"/></node>
+        <node id="5" lat="0.003" lon="0.001"/>
+        <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+        <way id="12"><nd ref="2"/><nd ref="4"/><tag k="highway" v="service"/></way>
+        <way id="13"><nd ref="4"/><nd ref="5"/><tag k="highway" v="path"/></way>
+    "#;
+
+    fn change(xml: &str) -> Change {
+        let path = temp_file(xml);
+        let change = xml::read_change(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        change
+    }
+
+    // Every way with the positions of its nodes, in id order
+    fn way_positions(osm: &OSM) -> Vec<(i64, Vec<DVec2>)> {
+        let mut ways: Vec<_> = osm
+            .ways()
+            .iter()
+            .map(|way| {
+                let positions = way.refs.iter().map(|&node| osm.nodes()[node].pos);
+                (way.id, positions.collect())
+            })
+            .collect();
+        ways.sort_by_key(|(id, _)| *id);
+        ways
+    }
+
+    #[test]
+    fn applies_creates_modifies_and_deletes() {
+        let osm = load(BEFORE, &LoadOptions::default()).apply(change(CHANGE));
+        let after = load(AFTER, &LoadOptions::default());
+        assert_eq!(way_positions(&osm), way_positions(&after));
+        let service = osm.ways().iter().find(|way| way.id == 12).unwrap();
+        assert_eq!(service.tag("highway"), Some("service"));
+        let mut nodes: Vec<_> = osm.nodes().iter().map(|node| node.id).collect();
+        nodes.sort();
+        assert_eq!(nodes, [1, 2, 4, 5]);
+    }
+
+    #[test]
+    fn changes_under_a_filter_or_clip_match_loading_the_result() {
+        let base = load(BEFORE, &LoadOptions::default()).apply(change(CHANGE));
+        for options in [
+            LoadOptions {
+                filter: Some("highway".parse().unwrap()),
+                ..Default::default()
+            },
+            LoadOptions {
+                clip: Some("-0.001,-0.001,0.002,0.002".parse().unwrap()),
+                filter: Some("highway!=residential".parse().unwrap()),
+            },
+        ] {
+            let restricted = base.restrict(&options);
+            let loaded = load(AFTER, &options);
+            assert_eq!(way_positions(&restricted), way_positions(&loaded));
+            assert_eq!(restricted.nodes().len(), loaded.nodes().len());
+        }
+    }
 }
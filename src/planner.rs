@@ -0,0 +1,196 @@
+use std::sync::{Arc, OnceLock};
+
+use glam::DVec2;
+
+use crate::{
+    graph::{OSMGraph, Route},
+    hierarchy::ContractionHierarchy,
+    isochrone::Isochrone,
+    profile::Profile,
+    projection::Projection,
+    style::GpuStyle,
+    vertex::Vertex,
+};
+
+const ROUTE_COLOR: &str = "#00e5ff";
+const ROUTE_WIDTH: f32 = 6.0;
+const START_COLOR: &str = "#2ecc40";
+const END_COLOR: &str = "#ff4136";
+const MARKER_SIZE: f32 = 16.0;
+/// Isochrone thresholds in minutes when none are given
+const DEFAULT_ISOCHRONE_MINUTES: [f64; 3] = [5.0, 10.0, 15.0];
+
+/// Route planning in the viewer: the first click picks the start, the second the end
+/// and the route between them, and the next click starts over. In isochrone mode a
+/// click shows what can be reached from there instead.
+pub struct RoutePlanner {
+    /// Filled in once the road network is built, which may happen in the background
+    graph: Arc<OnceLock<OSMGraph>>,
+    /// Used instead of searching the graph once it's ready
+    hierarchy: Arc<OnceLock<ContractionHierarchy>>,
+    profile: Profile,
+    start: Option<usize>,
+    end: Option<usize>,
+    route: Option<Route>,
+    /// Thresholds of the isochrones in minutes
+    isochrone_minutes: Vec<f64>,
+    /// Clicks show isochrones instead of planning routes
+    isochrone_mode: bool,
+    isochrone: Option<Isochrone>,
+}
+
+impl RoutePlanner {
+    pub fn new(
+        graph: Arc<OnceLock<OSMGraph>>,
+        hierarchy: Arc<OnceLock<ContractionHierarchy>>,
+        profile: Profile,
+    ) -> Self {
+        Self {
+            graph,
+            hierarchy,
+            profile,
+            start: None,
+            end: None,
+            route: None,
+            isochrone_minutes: DEFAULT_ISOCHRONE_MINUTES.to_vec(),
+            isochrone_mode: false,
+            isochrone: None,
+        }
+    }
+
+    /// Switches to isochrone mode with these thresholds
+    pub fn show_isochrones(&mut self, minutes: Vec<f64>) {
+        self.isochrone_minutes = minutes;
+        self.isochrone_mode = true;
+    }
+
+    /// Switches between planning routes and showing isochrones, clearing what's shown
+    pub fn toggle_isochrones(&mut self) {
+        self.isochrone_mode = !self.isochrone_mode;
+        self.clear();
+        if self.isochrone_mode {
+            eprintln!("Click to show what's reachable from there");
+        } else {
+            eprintln!("Click to plan a route");
+        }
+    }
+
+    /// Snaps a clicked (lon, lat) position to the nearest road and routes once both
+    /// ends are set. Returns false if nothing changed.
+    pub fn click(&mut self, lon_lat: DVec2) -> bool {
+        let Some(graph) = self.graph.get() else {
+            eprintln!("The road network is still loading");
+            return false;
+        };
+        let Some(node) = graph.closest_node(lon_lat, self.profile) else {
+            eprintln!(
+                "No {} road near {:.5}, {:.5}",
+                self.profile, lon_lat.y, lon_lat.x
+            );
+            return false;
+        };
+        if self.isochrone_mode {
+            let isochrone = Isochrone::new(graph, node, self.profile, &self.isochrone_minutes);
+            eprintln!("Isochrone: {}", isochrone.summary(graph));
+            self.isochrone = Some(isochrone);
+            return true;
+        }
+        match (self.start, self.end) {
+            (Some(start), None) => {
+                self.end = Some(node);
+                // A* until the hierarchy is ready, both follow the turn restrictions
+                self.route = match self.hierarchy.get() {
+                    Some(hierarchy) => hierarchy.route(graph, start, node),
+                    None => graph.plan_path_a_star(start, node, self.profile),
+                };
+                match &self.route {
+                    Some(_) => eprintln!("Route: {}", self.summary()),
+                    None => eprintln!("No {} route between those points", self.profile),
+                }
+            }
+            _ => {
+                self.start = Some(node);
+                self.end = None;
+                self.route = None;
+            }
+        }
+        true
+    }
+
+    /// The route between the start and end, once both are set and connected
+    pub fn route(&self) -> Option<&Route> {
+        self.route.as_ref()
+    }
+
+    pub fn clear(&mut self) {
+        self.start = None;
+        self.end = None;
+        self.route = None;
+        self.isochrone = None;
+    }
+
+    /// Distance and travel time of the current route or what the isochrone covers,
+    /// empty without either
+    pub fn summary(&self) -> String {
+        if let (Some(isochrone), Some(graph)) = (&self.isochrone, self.graph.get()) {
+            return isochrone.summary(graph);
+        }
+        let Some(route) = &self.route else {
+            return String::new();
+        };
+        let minutes = (route.time / 60.0).round() as u64;
+        let time = if minutes < 60 {
+            format!("{} min", minutes)
+        } else {
+            format!("{} h {:02} min", minutes / 60, minutes % 60)
+        };
+        format!(
+            "{:.1} km, {} by {}",
+            route.distance / 1000.0,
+            time,
+            self.profile
+        )
+    }
+
+    /// Vertices, line indices, fill indices and styles for `Graphics::set_overlay`: the
+    /// route as a thick line, then a dot at each end, or the isochrone
+    pub fn overlay(
+        &self,
+        projection: Projection,
+        origin: DVec2,
+    ) -> (Vec<Vertex>, Vec<u32>, Vec<u32>, Vec<GpuStyle>) {
+        let styles = vec![
+            GpuStyle::overlay(ROUTE_COLOR, ROUTE_WIDTH),
+            GpuStyle::overlay(START_COLOR, MARKER_SIZE),
+            GpuStyle::overlay(END_COLOR, MARKER_SIZE),
+        ];
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        let Some(graph) = self.graph.get() else {
+            return (vertices, indices, Vec::new(), styles);
+        };
+        if let Some(isochrone) = &self.isochrone {
+            return isochrone.overlay(graph, projection, origin);
+        }
+        let mut strip = |nodes: &[usize], style: u32| {
+            if !indices.is_empty() {
+                indices.push(u32::MAX);
+            }
+            for &node in nodes {
+                let pos = projection.project(graph.nodes()[node].pos) - origin;
+                indices.push(vertices.len() as u32);
+                vertices.push(Vertex::new(pos, style));
+            }
+        };
+        if let Some(route) = &self.route {
+            strip(&route.nodes, 0);
+        }
+        if let Some(start) = self.start {
+            strip(&[start, start], 1);
+        }
+        if let Some(end) = self.end {
+            strip(&[end, end], 2);
+        }
+        (vertices, indices, Vec::new(), styles)
+    }
+}
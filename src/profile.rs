@@ -0,0 +1,295 @@
+use std::str::FromStr;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::graph::{AccessValue, Edge, Highway, Surface};
+
+// Fastest any car goes, also what `maxspeed=none` is capped at
+const CAR_TOP_SPEED: f64 = 130.0;
+
+/// How a route is travelled, deciding which ways can be used and how fast
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Profile {
+    #[default]
+    Car,
+    Bike,
+    Foot,
+}
+
+impl Profile {
+    /// Seconds to travel `edge`, or `None` if it can't be used
+    pub fn time(self, edge: &Edge) -> Option<f64> {
+        let speed = self.speed(edge)?;
+        Some(edge.length / (speed / 3.6))
+    }
+
+    /// Highest speed `time` uses on any edge, in meters per second
+    pub fn max_speed(self) -> f64 {
+        let km_h = match self {
+            Profile::Car => CAR_TOP_SPEED,
+            Profile::Bike => 20.0,
+            Profile::Foot => 5.0,
+        };
+        km_h / 3.6
+    }
+
+    /// Keys naming this mode in turn restrictions, as in `restriction:<key>` and
+    /// `except=<key>`. Empty for modes turn restrictions don't apply to.
+    pub fn restriction_modes(self) -> &'static [&'static str] {
+        match self {
+            Profile::Car => &["motorcar", "motor_vehicle"],
+            Profile::Bike => &["bicycle"],
+            Profile::Foot => &[],
+        }
+    }
+
+    /// Speed on `edge` in km/h
+    pub fn speed(self, edge: &Edge) -> Option<f64> {
+        let access = self.access(edge);
+        if matches!(access, Some(AccessValue::No | AccessValue::Private)) {
+            return None;
+        }
+        let contraflow = match self {
+            Profile::Car => false,
+            Profile::Bike => edge.bicycle_contraflow,
+            Profile::Foot => true,
+        };
+        if edge.against_oneway && !contraflow {
+            return None;
+        }
+        // A tag for this mode like `bicycle=yes` opens up ways the class wouldn't allow,
+        // a general `access=yes` doesn't
+        let speed = match self.class_speed(edge.highway) {
+            Some(speed) => speed,
+            None if self.mode_access(edge).is_some() => self.fallback_speed(),
+            None => return None,
+        };
+        let speed = match (self, edge.maxspeed) {
+            (Profile::Car, Some(maxspeed)) => (maxspeed as f64).min(CAR_TOP_SPEED),
+            (Profile::Bike, Some(maxspeed)) => speed.min(maxspeed as f64),
+            _ => speed,
+        };
+        let surface_factor = match (self, edge.surface) {
+            (Profile::Foot, _) | (_, None | Some(Surface::Paved)) => 1.0,
+            (Profile::Car, Some(Surface::Unpaved)) => 0.6,
+            (Profile::Car, Some(Surface::Rough)) => 0.4,
+            (Profile::Bike, Some(Surface::Unpaved)) => 0.75,
+            (Profile::Bike, Some(Surface::Rough)) => 0.5,
+        };
+        Some(speed * surface_factor)
+    }
+
+    // The most specific access tag that's set for this mode
+    fn access(self, edge: &Edge) -> Option<AccessValue> {
+        let access = &edge.access;
+        match self {
+            Profile::Car => access
+                .motorcar
+                .or(access.motor_vehicle)
+                .or(access.vehicle)
+                .or(access.access),
+            Profile::Bike => access.bicycle.or(access.vehicle).or(access.access),
+            Profile::Foot => access.foot.or(access.access),
+        }
+    }
+
+    fn mode_access(self, edge: &Edge) -> Option<AccessValue> {
+        let access = &edge.access;
+        match self {
+            Profile::Car => access.motorcar.or(access.motor_vehicle),
+            Profile::Bike => access.bicycle,
+            Profile::Foot => access.foot,
+        }
+    }
+
+    // Typical speed in km/h when nothing else is known, `None` where the mode isn't
+    // allowed by default
+    fn class_speed(self, highway: Highway) -> Option<f64> {
+        use Highway::*;
+        match self {
+            Profile::Car => match highway {
+                Motorway => Some(110.0),
+                Trunk => Some(90.0),
+                Primary => Some(70.0),
+                Secondary => Some(60.0),
+                Tertiary => Some(50.0),
+                MotorwayLink | TrunkLink | PrimaryLink | SecondaryLink | TertiaryLink => Some(45.0),
+                Unclassified => Some(40.0),
+                Residential | Road => Some(30.0),
+                Service | Track => Some(15.0),
+                LivingStreet => Some(10.0),
+                Pedestrian | Footway | Path | Cycleway | Bridleway | Steps => None,
+            },
+            Profile::Bike => match highway {
+                Motorway | MotorwayLink | Trunk | TrunkLink => None,
+                Cycleway => Some(20.0),
+                Track | Path => Some(14.0),
+                LivingStreet => Some(10.0),
+                // Walking the bike
+                Pedestrian | Footway => Some(5.0),
+                Steps => Some(2.0),
+                Bridleway => None,
+                _ => Some(18.0),
+            },
+            Profile::Foot => match highway {
+                Motorway | MotorwayLink | Trunk | TrunkLink => None,
+                Steps => Some(3.0),
+                _ => Some(5.0),
+            },
+        }
+    }
+
+    fn fallback_speed(self) -> f64 {
+        match self {
+            Profile::Car => 30.0,
+            Profile::Bike => 14.0,
+            Profile::Foot => 5.0,
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "car" => Ok(Profile::Car),
+            "bike" | "bicycle" => Ok(Profile::Bike),
+            "foot" | "walk" => Ok(Profile::Foot),
+            _ => bail!("Unknown profile `{}`", s),
+        }
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Profile::Car => "car",
+            Profile::Bike => "bike",
+            Profile::Foot => "foot",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::Access;
+
+    const HIGHWAYS: [&str; 22] = [
+        "motorway",
+        "motorway_link",
+        "trunk",
+        "trunk_link",
+        "primary",
+        "primary_link",
+        "secondary",
+        "secondary_link",
+        "tertiary",
+        "tertiary_link",
+        "unclassified",
+        "residential",
+        "living_street",
+        "service",
+        "road",
+        "track",
+        "pedestrian",
+        "footway",
+        "path",
+        "cycleway",
+        "bridleway",
+        "steps",
+    ];
+
+    // A kilometer of `highway`
+    fn edge(highway: &str) -> Edge {
+        Edge {
+            to: 0,
+            way: 0,
+            length: 1_000.0,
+            highway: Highway::parse(highway).unwrap(),
+            maxspeed: None,
+            access: Access::default(),
+            surface: None,
+            against_oneway: false,
+            bicycle_contraflow: false,
+        }
+    }
+
+    #[test]
+    fn speeds_follow_the_tags() {
+        let mut primary = edge("primary");
+        assert_eq!(Profile::Car.speed(&primary), Some(70.0));
+        primary.maxspeed = Some(f32::INFINITY);
+        assert_eq!(Profile::Car.speed(&primary), Some(CAR_TOP_SPEED));
+        primary.maxspeed = Some(10.0);
+        assert_eq!(Profile::Car.speed(&primary), Some(10.0));
+        assert_eq!(Profile::Bike.speed(&primary), Some(10.0));
+        assert_eq!(Profile::Foot.speed(&primary), Some(5.0));
+        primary.surface = Some(Surface::Rough);
+        assert_eq!(Profile::Car.speed(&primary), Some(4.0));
+        assert_eq!(Profile::Car.time(&primary), Some(900.0));
+
+        let mut against = edge("residential");
+        against.against_oneway = true;
+        assert_eq!(Profile::Car.speed(&against), None);
+        assert_eq!(Profile::Bike.speed(&against), None);
+        assert_eq!(Profile::Foot.speed(&against), Some(5.0));
+        against.bicycle_contraflow = true;
+        assert_eq!(Profile::Bike.speed(&against), Some(18.0));
+
+        let mut footway = edge("footway");
+        assert_eq!(Profile::Car.speed(&footway), None);
+        footway.access.access = Some(AccessValue::Yes);
+        assert_eq!(Profile::Car.speed(&footway), None);
+        footway.access.motor_vehicle = Some(AccessValue::Yes);
+        assert_eq!(Profile::Car.speed(&footway), Some(30.0));
+        footway.access.motorcar = Some(AccessValue::Private);
+        assert_eq!(Profile::Car.speed(&footway), None);
+    }
+
+    // The A* heuristic divides the straight line distance by `max_speed`, so it only
+    // finds the fastest route if no edge is quicker than that
+    #[test]
+    fn max_speed_bounds_every_speed() {
+        let maxspeeds = [
+            None,
+            Some(5.0),
+            Some(50.0),
+            Some(300.0),
+            Some(f32::INFINITY),
+        ];
+        let surfaces = [None, Some(Surface::Paved), Some(Surface::Rough)];
+        let accesses = [None, Some(AccessValue::Yes), Some(AccessValue::Designated)];
+        for profile in [Profile::Car, Profile::Bike, Profile::Foot] {
+            let mut fastest: f64 = 0.0;
+            for highway in HIGHWAYS {
+                for maxspeed in maxspeeds {
+                    for surface in surfaces {
+                        for access in accesses {
+                            let edge = Edge {
+                                maxspeed,
+                                surface,
+                                access: Access {
+                                    motorcar: access,
+                                    bicycle: access,
+                                    foot: access,
+                                    ..Access::default()
+                                },
+                                ..edge(highway)
+                            };
+                            if let Some(speed) = profile.speed(&edge) {
+                                fastest = fastest.max(speed / 3.6);
+                                let time = profile.time(&edge).unwrap();
+                                assert!(time >= edge.length / profile.max_speed());
+                            }
+                        }
+                    }
+                }
+            }
+            // Tight as well, or the search explores more than it needs to
+            assert_eq!(fastest, profile.max_speed(), "{}", profile);
+        }
+    }
+}
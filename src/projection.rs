@@ -0,0 +1,112 @@
+use std::str::FromStr;
+
+use anyhow::bail;
+use glam::DVec2;
+use serde::{Deserialize, Serialize};
+
+// Web Mercator cuts off the poles where y goes to infinity
+const MAX_MERCATOR_LAT: f64 = 85.051_128_779_806_59;
+const METERS_PER_DEGREE: f64 = 111_319.490_793_273_58;
+
+/// Maps (lon, lat) in degrees to world units. Both projections keep one unit per
+/// degree of longitude, so the world is 360 units wide either way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Projection {
+    #[default]
+    WebMercator,
+    Equirectangular,
+}
+
+impl Projection {
+    pub fn project(self, lon_lat: DVec2) -> DVec2 {
+        match self {
+            Projection::WebMercator => {
+                let lat = lon_lat
+                    .y
+                    .clamp(-MAX_MERCATOR_LAT, MAX_MERCATOR_LAT)
+                    .to_radians();
+                let y = (std::f64::consts::FRAC_PI_4 + lat / 2.0).tan().ln();
+                DVec2::new(lon_lat.x, y.to_degrees())
+            }
+            Projection::Equirectangular => lon_lat,
+        }
+    }
+
+    pub fn unproject(self, world: DVec2) -> DVec2 {
+        match self {
+            Projection::WebMercator => {
+                let lat = 2.0 * world.y.to_radians().exp().atan() - std::f64::consts::FRAC_PI_2;
+                DVec2::new(world.x, lat.to_degrees())
+            }
+            Projection::Equirectangular => world,
+        }
+    }
+
+    /// Ground distance covered by one world unit along x at the given latitude
+    pub fn meters_per_unit(self, lat: f64) -> f64 {
+        METERS_PER_DEGREE * lat.to_radians().cos()
+    }
+}
+
+/// Great-circle distance between two (lon, lat) points in meters
+pub fn distance_meters(a: DVec2, b: DVec2) -> f64 {
+    let earth_radius = METERS_PER_DEGREE.to_degrees();
+    let (a, b) = (
+        a * std::f64::consts::PI / 180.0,
+        b * std::f64::consts::PI / 180.0,
+    );
+    let half = (b - a) / 2.0;
+    let h = half.y.sin().powi(2) + a.y.cos() * b.y.cos() * half.x.sin().powi(2);
+    2.0 * earth_radius * h.sqrt().min(1.0).asin()
+}
+
+impl FromStr for Projection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mercator" | "web-mercator" => Ok(Projection::WebMercator),
+            "equirectangular" | "plate-carree" => Ok(Projection::Equirectangular),
+            _ => bail!("Unknown projection `{}`", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unproject_undoes_project() {
+        for projection in [Projection::WebMercator, Projection::Equirectangular] {
+            for lon in [-180.0, -73.98, 0.0, 13.4, 179.9] {
+                for lat in [-85.0, -45.5, 0.0, 0.001, 52.52, 60.0, 85.0] {
+                    let lon_lat = DVec2::new(lon, lat);
+                    let world = projection.project(lon_lat);
+                    assert_eq!(world.x, lon);
+                    let back = projection.unproject(world);
+                    assert!(back.distance(lon_lat) < 1e-9, "{:?} {:?}", projection, back);
+                }
+            }
+        }
+
+        // The cut-off latitude makes the mercator world square
+        let corner = Projection::WebMercator.project(DVec2::new(180.0, MAX_MERCATOR_LAT));
+        assert!(corner.distance(DVec2::new(180.0, 180.0)) < 1e-9);
+        let pole = Projection::WebMercator.project(DVec2::new(0.0, 90.0));
+        assert_eq!(pole, DVec2::new(0.0, corner.y));
+        assert!((Projection::WebMercator.unproject(pole).y - MAX_MERCATOR_LAT).abs() < 1e-9);
+    }
+
+    #[test]
+    fn distances_in_meters() {
+        let degree = distance_meters(DVec2::new(0.0, 0.0), DVec2::new(1.0, 0.0));
+        assert!((degree - METERS_PER_DEGREE).abs() < 1e-6);
+        let north = distance_meters(DVec2::new(10.0, 60.0), DVec2::new(10.0, 61.0));
+        assert!((north - METERS_PER_DEGREE).abs() < 1e-6);
+        // At 60° north a degree east is half as long as a degree north
+        let east = distance_meters(DVec2::new(10.0, 60.0), DVec2::new(10.001, 60.0));
+        let expected = Projection::WebMercator.meters_per_unit(60.0) * 0.001;
+        assert!((east - expected).abs() < 1e-3);
+    }
+}
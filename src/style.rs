@@ -0,0 +1,414 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{bail, Context, Result};
+use glam::Vec4;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_STYLE: &str = include_str!("../styles/default.toml");
+
+/// Colors the styles of each file drawn as its own layer are mixed with
+const LAYER_COLORS: [&str; 6] = [
+    "#e41a1c", "#377eb8", "#4daf4a", "#984ea3", "#ff7f00", "#a65628",
+];
+
+// Shared with the shader, keep in sync with `Style` in shader.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Serialize, Deserialize)]
+pub struct GpuStyle {
+    pub color: Vec4,
+    /// Fill for closed ways and multipolygons, transparent when they aren't filled
+    pub fill: Vec4,
+    pub width: f32,
+    pub min_zoom: f32,
+    pub max_zoom: f32,
+    pub z_order: f32,
+    pub width_unit: u32,
+    pub join: u32,
+    pub cap: u32,
+    _padding: u32,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum WidthUnit {
+    #[default]
+    Px,
+    M,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum Join {
+    #[default]
+    Round,
+    Miter,
+}
+
+// Values match the `END_*` constants in shader.wgsl
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum Cap {
+    Butt = 1,
+    Square = 2,
+    #[default]
+    Round = 3,
+}
+
+#[derive(Deserialize)]
+struct RawStyle {
+    color: String,
+    #[serde(default)]
+    fill: Option<String>,
+    #[serde(default = "default_width")]
+    width: f32,
+    #[serde(default)]
+    width_unit: WidthUnit,
+    #[serde(default)]
+    join: Join,
+    #[serde(default)]
+    cap: Cap,
+    #[serde(default)]
+    z: i32,
+    #[serde(default)]
+    min_zoom: Option<f32>,
+    #[serde(default)]
+    max_zoom: Option<f32>,
+}
+
+fn default_width() -> f32 {
+    1.0
+}
+
+#[derive(Deserialize)]
+struct RawRule {
+    selector: String,
+    #[serde(flatten)]
+    style: RawStyle,
+}
+
+#[derive(Deserialize)]
+struct RawSheet {
+    default: RawStyle,
+    #[serde(default, rename = "rule")]
+    rules: Vec<RawRule>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    // `key=*` or a bare `key`
+    Has(String),
+    Equals(String, String),
+}
+
+impl Condition {
+    /// `key=value`, `key=*` or a bare `key`
+    pub fn parse(condition: &str) -> Result<Self> {
+        match condition.split_once('=') {
+            Some(("", _)) => bail!("Missing key in `{}`", condition),
+            Some((key, "*")) | Some((key, "")) => Ok(Condition::Has(key.to_owned())),
+            Some((key, value)) => Ok(Condition::Equals(key.to_owned(), value.to_owned())),
+            None => Ok(Condition::Has(condition.to_owned())),
+        }
+    }
+
+    pub fn matches(&self, tags: &HashMap<String, String>) -> bool {
+        match self {
+            Condition::Has(key) => tags.contains_key(key),
+            Condition::Equals(key, value) => tags.get(key) == Some(value),
+        }
+    }
+}
+
+/// Whitespace separated `key=value`, `key=*` or `key` conditions which all have to match
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector(Vec<Condition>);
+
+impl Selector {
+    pub fn parse(selector: &str) -> Result<Self> {
+        let conditions = selector
+            .split_whitespace()
+            .map(Condition::parse)
+            .collect::<Result<Vec<_>>>()?;
+        if conditions.is_empty() {
+            bail!("Empty selector");
+        }
+        Ok(Self(conditions))
+    }
+
+    pub fn matches(&self, tags: &HashMap<String, String>) -> bool {
+        self.0.iter().all(|condition| condition.matches(tags))
+    }
+}
+
+struct Rule {
+    selector: Selector,
+    style: u32,
+}
+
+/// Maps way tags to an index into `styles`. Index 0 is the default style for ways
+/// that no rule matches, otherwise the first matching rule wins.
+pub struct StyleSheet {
+    rules: Vec<Rule>,
+    styles: Vec<GpuStyle>,
+    source: String,
+}
+
+impl StyleSheet {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read style {}", path.display()))?;
+        Self::parse(&source).with_context(|| format!("Invalid style {}", path.display()))
+    }
+
+    pub fn parse(source: &str) -> Result<Self> {
+        let sheet: RawSheet = toml::from_str(source)?;
+        let mut styles = vec![sheet.default.to_gpu()?];
+        let mut rules = Vec::new();
+        for rule in sheet.rules {
+            rules.push(Rule {
+                selector: Selector::parse(&rule.selector)
+                    .with_context(|| format!("Invalid selector `{}`", rule.selector))?,
+                style: styles.len() as u32,
+            });
+            styles.push(
+                rule.style
+                    .to_gpu()
+                    .with_context(|| format!("Invalid rule `{}`", rule.selector))?,
+            );
+        }
+        Ok(Self {
+            rules,
+            styles,
+            source: source.to_owned(),
+        })
+    }
+
+    pub fn style_for(&self, tags: &HashMap<String, String>) -> u32 {
+        self.rules
+            .iter()
+            .find(|rule| rule.selector.matches(tags))
+            .map_or(0, |rule| rule.style)
+    }
+
+    pub fn styles(&self) -> &[GpuStyle] {
+        &self.styles
+    }
+
+    /// The text the sheet was parsed from
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn has_fill(&self, style: u32) -> bool {
+        self.styles[style as usize].fill.w > 0.0
+    }
+}
+
+impl Default for StyleSheet {
+    fn default() -> Self {
+        Self::parse(DEFAULT_STYLE).expect("Built-in style is invalid")
+    }
+}
+
+impl GpuStyle {
+    /// Style for markers and lines drawn over the map, shown at every zoom level
+    pub fn overlay(color: &str, width: f32) -> Self {
+        RawStyle {
+            color: color.to_owned(),
+            fill: None,
+            width,
+            width_unit: WidthUnit::Px,
+            join: Join::Round,
+            cap: Cap::Round,
+            z: 0,
+            min_zoom: None,
+            max_zoom: None,
+        }
+        .to_gpu()
+        .unwrap()
+    }
+
+    /// Style for areas drawn over the map, filled with `fill` and without an outline
+    pub fn overlay_area(fill: &str) -> Self {
+        RawStyle {
+            color: "#00000000".to_owned(),
+            fill: Some(fill.to_owned()),
+            width: 0.0,
+            width_unit: WidthUnit::Px,
+            join: Join::Round,
+            cap: Cap::Round,
+            z: 0,
+            min_zoom: None,
+            max_zoom: None,
+        }
+        .to_gpu()
+        .unwrap()
+    }
+
+    /// This style mixed halfway towards the color of `layer`, keeping its alpha
+    pub fn for_layer(&self, layer: usize) -> Self {
+        let tint = parse_color(LAYER_COLORS[layer % LAYER_COLORS.len()]).unwrap();
+        let mix = |color: Vec4| color.lerp(tint, 0.5).truncate().extend(color.w);
+        Self {
+            color: mix(self.color),
+            fill: mix(self.fill),
+            ..*self
+        }
+    }
+}
+
+impl RawStyle {
+    fn to_gpu(&self) -> Result<GpuStyle> {
+        let min_zoom = self.min_zoom.unwrap_or(f32::MIN);
+        let max_zoom = self.max_zoom.unwrap_or(f32::MAX);
+        if min_zoom > max_zoom {
+            bail!("min_zoom {} is above max_zoom {}", min_zoom, max_zoom);
+        }
+        Ok(GpuStyle {
+            color: parse_color(&self.color)?,
+            fill: match &self.fill {
+                Some(fill) => parse_color(fill)?,
+                None => Vec4::ZERO,
+            },
+            width: self.width,
+            min_zoom,
+            max_zoom,
+            z_order: self.z as f32,
+            width_unit: self.width_unit as u32,
+            join: self.join as u32,
+            cap: self.cap as u32,
+            _padding: 0,
+        })
+    }
+}
+
+// `#rrggbb` or `#rrggbbaa`, converted to linear since the surface is sRGB
+fn parse_color(color: &str) -> Result<Vec4> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+        bail!("Invalid color `{}`", color);
+    }
+    let channel = |i: usize| -> Result<f32> {
+        let value = u8::from_str_radix(&hex[i..i + 2], 16)
+            .with_context(|| format!("Invalid color `{}`", color))?;
+        Ok(value as f32 / 255.0)
+    };
+    let to_linear = |c: f32| {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let alpha = if hex.len() == 8 { channel(6)? } else { 1.0 };
+    Ok(Vec4::new(
+        to_linear(channel(0)?),
+        to_linear(channel(2)?),
+        to_linear(channel(4)?),
+        alpha,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    fn error(source: &str) -> String {
+        match StyleSheet::parse(source) {
+            Ok(_) => panic!("parsed an invalid style"),
+            Err(err) => format!("{:#}", err),
+        }
+    }
+
+    #[test]
+    fn parses_selectors() {
+        let selector = Selector::parse(" highway=primary  name bridge=* tunnel= ").unwrap();
+        assert_eq!(
+            selector,
+            Selector(vec![
+                Condition::Equals("highway".into(), "primary".into()),
+                Condition::Has("name".into()),
+                Condition::Has("bridge".into()),
+                Condition::Has("tunnel".into()),
+            ])
+        );
+        let road = [("highway", "primary"), ("name", "A1"), ("bridge", "yes")];
+        assert!(!selector.matches(&tags(&road)));
+        assert!(selector.matches(&tags(&[road[0], road[1], road[2], ("tunnel", "no")])));
+
+        assert_eq!(
+            Selector::parse("").unwrap_err().to_string(),
+            "Empty selector"
+        );
+        assert_eq!(
+            Selector::parse("highway =primary").unwrap_err().to_string(),
+            "Missing key in `=primary`"
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let sheet = StyleSheet::parse(
+            r##"
+            default = { color = "#000000" }
+            [[rule]]
+            selector = "highway=motorway"
+            color = "#ff0000"
+            min_zoom = 5
+            [[rule]]
+            selector = "highway"
+            color = "#00ff00"
+            width = 3
+            max_zoom = 14.5
+            "##,
+        )
+        .unwrap();
+        assert_eq!(sheet.style_for(&tags(&[("highway", "motorway")])), 1);
+        assert_eq!(sheet.style_for(&tags(&[("highway", "path")])), 2);
+        assert_eq!(sheet.style_for(&tags(&[("building", "yes")])), 0);
+
+        let zooms = |style: &GpuStyle| (style.min_zoom, style.max_zoom);
+        assert_eq!(zooms(&sheet.styles()[0]), (f32::MIN, f32::MAX));
+        assert_eq!(zooms(&sheet.styles()[1]), (5.0, f32::MAX));
+        assert_eq!(zooms(&sheet.styles()[2]), (f32::MIN, 14.5));
+        assert_eq!(sheet.styles()[2].width, 3.0);
+        assert_eq!(sheet.styles()[1].color, Vec4::new(1.0, 0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn rejects_invalid_styles() {
+        let rule = |rule: &str| {
+            error(&format!(
+                "default = {{ color = \"#000000\" }}\n[[rule]]\n{}",
+                rule
+            ))
+        };
+        assert!(rule("selector = \"=motorway\"\ncolor = \"#ff0000\"")
+            .starts_with("Invalid selector `=motorway`: Missing key"));
+        assert_eq!(
+            rule("selector = \"highway\"\ncolor = \"#ff0000\"\nmin_zoom = 15\nmax_zoom = 12"),
+            "Invalid rule `highway`: min_zoom 15 is above max_zoom 12"
+        );
+        assert_eq!(
+            rule("selector = \"highway\"\ncolor = \"red\""),
+            "Invalid rule `highway`: Invalid color `red`"
+        );
+        assert!(
+            rule("selector = \"highway\"\ncolor = \"#ff0000\"\nmin_zoom = \"high\"")
+                .contains("invalid type: string \"high\", expected f32")
+        );
+        assert!(
+            error("[[rule]]\nselector = \"highway\"\ncolor = \"#ff0000\"")
+                .contains("missing field `default`")
+        );
+        assert!(StyleSheet::parse(DEFAULT_STYLE).is_ok());
+    }
+}
@@ -1,14 +1,30 @@
-use glam::{Vec2};
+use glam::{DVec2, Vec2};
 use serde::{Deserialize, Serialize};
 
+use crate::camera::split_f64;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Serialize, Deserialize)]
 pub struct Vertex {
     pub pos: Vec2,
+    /// What `pos` lost to f32 rounding
+    pub pos_low: Vec2,
+    /// Index into the style buffer
+    pub style: u32,
 }
 
 impl Vertex {
-    const ATTRIBS: [wgpu::VertexAttribute; 1] = wgpu::vertex_attr_array![0 => Float32x2];
+    const ATTRIBS: [wgpu::VertexAttribute; 3] =
+        wgpu::vertex_attr_array![0 => Float32x2, 2 => Float32x2, 1 => Uint32];
+
+    pub fn new(pos: DVec2, style: u32) -> Self {
+        let (pos, pos_low) = split_f64(pos);
+        Self {
+            pos,
+            pos_low,
+            style,
+        }
+    }
 
     pub fn desc() -> wgpu::VertexBufferLayout<'static> {
         use std::mem;
@@ -0,0 +1,416 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use glam::DVec2;
+use quick_xml::events::{BytesStart, Event};
+
+use crate::{
+    filter::Filter,
+    osm::{Block, Change, Member, MemberKind, Relation, TempNode, Way},
+};
+
+/// Opens `path` as OSM XML, decompressing gzip and bzip2 on the fly. The format is
+/// taken from the first bytes rather than the extension, anything that isn't XML
+/// gives `None` and is read as PBF.
+pub fn open(path: &Path) -> Result<Option<Box<dyn BufRead>>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut reader: Box<dyn BufRead> = match file.fill_buf()? {
+        [0x1f, 0x8b, ..] => Box::new(BufReader::new(MultiGzDecoder::new(file))),
+        [b'B', b'Z', b'h', ..] => Box::new(BufReader::new(MultiBzDecoder::new(file))),
+        _ => Box::new(file),
+    };
+    if is_xml(reader.fill_buf()?) {
+        Ok(Some(reader))
+    } else {
+        Ok(None)
+    }
+}
+
+fn is_xml(start: &[u8]) -> bool {
+    let start = start.strip_prefix(b"\xef\xbb\xbf").unwrap_or(start);
+    start
+        .iter()
+        .find(|byte| !byte.is_ascii_whitespace())
+        .is_some_and(|&byte| byte == b'<')
+}
+
+// The element currently being read, its children are added as they come
+enum Current {
+    None,
+    Node(TempNode),
+    Way(Way, Vec<i64>),
+    Relation(Relation),
+    /// Deleted in a JOSM export, its children are ignored
+    Skipped,
+}
+
+/// Reads `<node>`, `<way>` and `<relation>` elements into `block`
+pub fn read(reader: impl BufRead, block: &mut Block, filter: Option<&Filter>) -> Result<()> {
+    parse(reader, block, None, filter)
+}
+
+/// Reads an OsmChange file, whose elements are inside `<create>`, `<modify>` and
+/// `<delete>` sections
+pub fn read_change(path: &Path) -> Result<Change> {
+    let reader = open(path)?.context("Not an XML file")?;
+    let mut change = Change::default();
+    parse(reader, &mut change.block, Some(&mut change.removed), None)?;
+    Ok(change)
+}
+
+// Without `removed` there are no sections and every element is read into `block`
+fn parse(
+    reader: impl BufRead,
+    block: &mut Block,
+    mut removed: Option<&mut HashSet<(MemberKind, i64)>>,
+    filter: Option<&Filter>,
+) -> Result<()> {
+    let mut reader = quick_xml::Reader::from_reader(reader);
+    let mut buf = Vec::new();
+    let mut current = Current::None;
+    let mut deleting = false;
+    loop {
+        let event = reader
+            .read_event_into(&mut buf)
+            .with_context(|| format!("Invalid XML at byte {}", reader.buffer_position()))?;
+        match event {
+            Event::Start(ref element) | Event::Empty(ref element) => {
+                let attributes = attributes(element)?;
+                let id = || -> Result<i64> {
+                    let id = attributes.get("id").context("Missing id")?;
+                    id.parse().with_context(|| format!("Invalid id `{}`", id))
+                };
+                let name = element.name();
+                if let (Some(removed), Some(kind)) = (&mut removed, element_kind(name.as_ref())) {
+                    removed.insert((kind, id()?));
+                }
+                match name.as_ref() {
+                    b"delete" => deleting = true,
+                    b"node" | b"way" | b"relation" if deleting || is_deleted(&attributes) => {
+                        current = Current::Skipped;
+                    }
+                    b"node" => {
+                        let coordinate = |key| -> Result<f64> {
+                            let value = attributes.get(key).context("Node without position")?;
+                            value
+                                .parse()
+                                .with_context(|| format!("Invalid {} `{}`", key, value))
+                        };
+                        current = Current::Node(TempNode {
+                            id: id()?,
+                            pos: DVec2::new(coordinate("lon")?, coordinate("lat")?),
+                            tags: HashMap::new(),
+                        });
+                    }
+                    b"way" => {
+                        let way = Way {
+                            id: id()?,
+                            tags: HashMap::new(),
+                            refs: Vec::new(),
+                        };
+                        current = Current::Way(way, Vec::new());
+                    }
+                    b"relation" => {
+                        current = Current::Relation(Relation {
+                            id: id()?,
+                            tags: HashMap::new(),
+                            members: Vec::new(),
+                        });
+                    }
+                    b"tag" => {
+                        let tags = match &mut current {
+                            Current::Node(node) => Some(&mut node.tags),
+                            Current::Way(way, _) => Some(&mut way.tags),
+                            Current::Relation(relation) => Some(&mut relation.tags),
+                            Current::None | Current::Skipped => None,
+                        };
+                        if let (Some(tags), Some(key), Some(value)) =
+                            (tags, attributes.get("k"), attributes.get("v"))
+                        {
+                            tags.insert(key.clone(), value.clone());
+                        }
+                    }
+                    b"nd" => {
+                        if let Current::Way(_, refs) = &mut current {
+                            let id = attributes.get("ref").context("<nd> without ref")?;
+                            refs.push(
+                                id.parse()
+                                    .with_context(|| format!("Invalid ref `{}`", id))?,
+                            );
+                        }
+                    }
+                    b"member" => {
+                        if let Current::Relation(relation) = &mut current {
+                            relation.members.push(member(&attributes)?);
+                        }
+                    }
+                    _ => {}
+                }
+                // Self-closing elements have no end event
+                if matches!(event, Event::Empty(_)) {
+                    finish(element.name().as_ref(), &mut current, block, filter);
+                }
+            }
+            Event::End(ref element) => {
+                if element.name().as_ref() == b"delete" {
+                    deleting = false;
+                }
+                finish(element.name().as_ref(), &mut current, block, filter);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(())
+}
+
+fn element_kind(name: &[u8]) -> Option<MemberKind> {
+    match name {
+        b"node" => Some(MemberKind::Node),
+        b"way" => Some(MemberKind::Way),
+        b"relation" => Some(MemberKind::Relation),
+        _ => None,
+    }
+}
+
+fn finish(name: &[u8], current: &mut Current, block: &mut Block, filter: Option<&Filter>) {
+    if element_kind(name).is_none() {
+        return;
+    }
+    match std::mem::replace(current, Current::None) {
+        Current::Node(node) => block.push_node(node),
+        Current::Way(way, refs) => block.push_way(way, refs, filter),
+        Current::Relation(relation) => block.push_relation(relation, filter),
+        Current::None | Current::Skipped => {}
+    }
+}
+
+fn attributes(element: &BytesStart) -> Result<HashMap<String, String>> {
+    let mut attributes = HashMap::new();
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        attributes.insert(
+            String::from_utf8_lossy(attribute.key.as_ref()).into_owned(),
+            attribute.unescape_value()?.into_owned(),
+        );
+    }
+    Ok(attributes)
+}
+
+fn is_deleted(attributes: &HashMap<String, String>) -> bool {
+    attributes
+        .get("action")
+        .is_some_and(|action| action == "delete")
+        || attributes
+            .get("visible")
+            .is_some_and(|visible| visible == "false")
+}
+
+fn member(attributes: &HashMap<String, String>) -> Result<Member> {
+    let kind = match attributes.get("type").map(String::as_str) {
+        Some("node") => MemberKind::Node,
+        Some("way") => MemberKind::Way,
+        Some("relation") => MemberKind::Relation,
+        Some(kind) => bail!("Unknown member type `{}`", kind),
+        None => bail!("Member without type"),
+    };
+    let id = attributes.get("ref").context("Member without ref")?;
+    Ok(Member {
+        kind,
+        id: id
+            .parse()
+            .with_context(|| format!("Invalid ref `{}`", id))?,
+        role: attributes.get("role").cloned().unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        path::PathBuf,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use crate::osm::{LoadOptions, OSM};
+
+    const SMALL: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <osm version="0.6">
+            <node id="1" lat="1.5" lon="2.5"><tag k="amenity" v="bench"/></node>
+            <node id="2" lat="1.0" lon="2.0"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="path"/></way>
+            <relation id="20"><member type="way" ref="10" role="outer"/></relation>
+        </osm>"#;
+
+    // Removed when dropped, so failing tests don't leave files behind
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(contents: &[u8]) -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "osm-view-xml-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn read_file(contents: &[u8]) -> Option<String> {
+        let file = TempFile::new(contents);
+        let mut reader = open(&file.0).unwrap()?;
+        let mut text = String::new();
+        reader.read_to_string(&mut text).unwrap();
+        Some(text)
+    }
+
+    fn load(xml: &str) -> OSM {
+        OSM::from_xml(xml, &LoadOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn sniffs_compression_from_the_contents() {
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzip.write_all(SMALL.as_bytes()).unwrap();
+        let mut bzip2 = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+        bzip2.write_all(SMALL.as_bytes()).unwrap();
+        for contents in [
+            SMALL.as_bytes().to_vec(),
+            gzip.finish().unwrap(),
+            bzip2.finish().unwrap(),
+        ] {
+            assert_eq!(read_file(&contents).as_deref(), Some(SMALL));
+        }
+    }
+
+    #[test]
+    fn anything_else_is_pbf() {
+        // The start of a PBF file, the length of the first blob header
+        assert_eq!(read_file(&[0, 0, 0, 13, 10, 9]), None);
+        assert_eq!(read_file(b"osm"), None);
+        assert_eq!(read_file(b""), None);
+    }
+
+    #[test]
+    fn skips_byte_order_marks_and_whitespace() {
+        let contents = format!("\u{feff}\n  {}", SMALL);
+        let text = read_file(contents.as_bytes()).unwrap();
+        assert_eq!(load(&text).nodes().len(), 2);
+    }
+
+    #[test]
+    fn reads_elements() {
+        let osm = load(SMALL);
+        let node = &osm.nodes()[0];
+        assert_eq!(node.id, 1);
+        assert_eq!(node.pos, DVec2::new(2.5, 1.5));
+        assert_eq!(node.tags["amenity"], "bench");
+        let way = &osm.ways()[0];
+        assert_eq!((way.id, way.refs.as_slice()), (10, [0, 1].as_slice()));
+        assert_eq!(way.tags["highway"], "path");
+        let member = &osm.relations()[0].members[0];
+        assert_eq!((member.kind, member.id), (MemberKind::Way, 10));
+        assert_eq!(member.role, "outer");
+    }
+
+    #[test]
+    fn skips_deleted_and_invisible_elements() {
+        let osm = load(
+            r#"<osm version="0.6" generator="JOSM">
+                <node id="1" lat="0" lon="0"/>
+                <node id="2" lat="0" lon="0" action="delete"><tag k="name" v="gone"/></node>
+                <node id="3" lat="0" lon="0" visible="false"/>
+                <node id="-4" lat="0" lon="0" action="modify"/>
+                <way id="10" action="delete"><nd ref="1"/><nd ref="2"/></way>
+                <way id="11" visible="true"><nd ref="1"/><nd ref="-4"/></way>
+                <relation id="20" visible="false"><member type="way" ref="11" role=""/></relation>
+            </osm>"#,
+        );
+        let nodes: Vec<_> = osm.nodes().iter().map(|node| node.id).collect();
+        assert_eq!(nodes, [1, -4]);
+        assert!(osm.nodes().iter().all(|node| node.tags.is_empty()));
+        let ways: Vec<_> = osm.ways().iter().map(|way| way.id).collect();
+        assert_eq!(ways, [11]);
+        assert!(osm.relations().is_empty());
+    }
+
+    #[test]
+    fn reports_malformed_elements() {
+        let mut block = Block::default();
+        for (xml, message) in [
+            (r#"<node lat="0" lon="0"/>"#, "Missing id"),
+            (r#"<node id="x" lat="0" lon="0"/>"#, "Invalid id `x`"),
+            (r#"<node id="1" lat="0"/>"#, "Node without position"),
+            (r#"<way id="1"><nd/></way>"#, "<nd> without ref"),
+            (
+                r#"<relation id="1"><member type="area" ref="1"/></relation>"#,
+                "Unknown member type `area`",
+            ),
+        ] {
+            let error = read(xml.as_bytes(), &mut block, None).unwrap_err();
+            assert_eq!(error.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn reads_change_sections() {
+        let file = TempFile::new(
+            br#"<osmChange version="0.6">
+                <create>
+                    <node id="5" lat="1" lon="1"/>
+                    <way id="11"><nd ref="1"/><nd ref="5"/><tag k="highway" v="path"/></way>
+                </create>
+                <modify>
+                    <node id="2" lat="2" lon="2"/>
+                </modify>
+                <delete>
+                    <way id="10"/>
+                    <node id="3" lat="0" lon="0"/>
+                </delete>
+            </osmChange>"#,
+        );
+        let change = read_change(&file.0).unwrap();
+        let mut removed: Vec<_> = change.removed.iter().copied().collect();
+        removed.sort_by_key(|&(kind, id)| (id, kind == MemberKind::Way));
+        assert_eq!(
+            removed,
+            [
+                (MemberKind::Node, 2),
+                (MemberKind::Node, 3),
+                (MemberKind::Node, 5),
+                (MemberKind::Way, 10),
+                (MemberKind::Way, 11),
+            ]
+        );
+
+        let osm = load(SMALL).apply(change);
+        let nodes: Vec<_> = osm.nodes().iter().map(|node| (node.id, node.pos)).collect();
+        assert_eq!(
+            nodes,
+            [
+                (1, DVec2::new(2.5, 1.5)),
+                (5, DVec2::new(1.0, 1.0)),
+                (2, DVec2::new(2.0, 2.0)),
+            ]
+        );
+        let ways: Vec<_> = osm.ways().iter().map(|way| way.id).collect();
+        assert_eq!(ways, [11]);
+    }
+}